@@ -0,0 +1,408 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+use serde::Deserialize;
+use discord_us::common::Subscription;
+use discord_us::credentials::Credential;
+use discord_us::http_client::{ClientProfile, HttpConfig};
+
+/// Environment variable read when no token is given on the command line
+pub const TOKEN_ENV: &str = "DISCORD_US_TOKEN";
+
+pub const DEFAULT_CACHE_SIZE: u64 = 1024 * 1024 * 1024;
+
+// Configuration file
+// Stored in $XDG_CONFIG_HOME/discord-us/config.toml, for example:
+//
+// default_profile = "main"
+//
+// [profiles.main]
+// token_file = "/home/me/.config/discord-us/token"
+// channel_id = 1234
+// subscription = "basic"
+// threads = 4
+//
+// [http]
+// proxy = "http://proxy.corp:3128"
+// connect_timeout = 10
+// ca_certificates = ["/etc/ssl/corp-ca.pem"]
+//
+// [client]
+// locale = "en-US"
+// timezone = "America/New_York"
+// super_properties = { client_build_number = 19201300113773 }
+//
+// [[profiles.team.pool]]
+// name = "alice"
+// token_env = "ALICE_TOKEN"
+// channel_id = 1234
+// concurrency = 2
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub default_profile: Option<String>,
+
+    /// Catalog of the uploads, $XDG_DATA_HOME/discord-us/catalog.db by default
+    pub catalog: Option<String>,
+
+    /// Cache of the chunks read by cat and extract, $XDG_CACHE_HOME/discord-us/chunks by default
+    pub cache_dir: Option<String>,
+    /// Size limit of the cache in bytes, 0 disables it
+    pub cache_size: Option<u64>,
+
+    #[serde(default)]
+    pub http: HttpSettings,
+
+    /// Identity sent to Discord, see `ClientProfile`
+    #[serde(default)]
+    pub client: ClientProfile,
+
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// Prefer `token_env` or `token_file` to keep the token out of the config
+    pub token: Option<String>,
+    /// Name of an environment variable holding the token
+    pub token_env: Option<String>,
+    /// File holding the token
+    pub token_file: Option<String>,
+
+    pub channel_id: Option<u64>,
+    /// Channel webhook url, used instead of the token and the channel
+    pub webhook: Option<String>,
+
+    /// Used for the container size when `container_size` is not set
+    pub subscription: Option<String>,
+    pub container_size: Option<usize>,
+    pub chunk_size: Option<u32>,
+
+    pub threads: Option<u32>,
+    pub kdf_iterations: Option<u32>,
+
+    /// The token is a bot token
+    pub bot: Option<bool>,
+
+    /// Tokens uploading the containers together, used instead of the profile token
+    #[serde(default)]
+    pub pool: Vec<PoolCredential>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PoolCredential {
+    /// Recorded in the waterfall, the position in the pool by default
+    pub name: Option<String>,
+    pub token: Option<String>,
+    pub token_env: Option<String>,
+    pub token_file: Option<String>,
+    /// The channel of the profile by default
+    pub channel_id: Option<u64>,
+    /// Containers uploaded at the same time with this token, 1 by default
+    pub concurrency: Option<usize>,
+}
+
+/// Client settings of every transfer, durations in seconds
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct HttpSettings {
+    pub proxy: Option<String>,
+    pub connect_timeout: Option<u64>,
+    /// 0 disables it
+    pub read_timeout: Option<u64>,
+    #[serde(default)]
+    pub http2: bool,
+    #[serde(default)]
+    pub ca_certificates: Vec<String>,
+    pub pool_max_idle_per_host: Option<usize>,
+    pub pool_idle_timeout: Option<u64>,
+    pub tcp_keepalive: Option<u64>,
+}
+
+impl Config {
+    pub fn default_path() -> Option<PathBuf> {
+        let config_home = match std::env::var_os("XDG_CONFIG_HOME") {
+            Some(path) if !path.is_empty() => PathBuf::from(path),
+            _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+        };
+
+        Some(config_home.join("discord-us").join("config.toml"))
+    }
+
+    pub fn catalog_path(&self) -> Option<PathBuf> {
+        if let Some(path) = &self.catalog {
+            return Some(PathBuf::from(path));
+        }
+
+        let data_home = match std::env::var_os("XDG_DATA_HOME") {
+            Some(path) if !path.is_empty() => PathBuf::from(path),
+            _ => PathBuf::from(std::env::var_os("HOME")?).join(".local").join("share"),
+        };
+
+        Some(data_home.join("discord-us").join("catalog.db"))
+    }
+
+    pub fn cache_dir(&self) -> Option<PathBuf> {
+        if let Some(path) = &self.cache_dir {
+            return Some(PathBuf::from(path));
+        }
+
+        let cache_home = match std::env::var_os("XDG_CACHE_HOME") {
+            Some(path) if !path.is_empty() => PathBuf::from(path),
+            _ => PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+        };
+
+        Some(cache_home.join("discord-us").join("chunks"))
+    }
+
+    /// Load the given file, or the default file if it exists
+    pub fn load(path: Option<String>) -> Result<Config, String> {
+        let path = match path {
+            Some(path) => PathBuf::from(path),
+            None => match Self::default_path() {
+                Some(path) if path.exists() => path,
+                _ => return Ok(Config::default()),
+            },
+        };
+
+        let content = std::fs::read_to_string(&path)
+            .map_err(|err| format!("Cannot read {}: {}", path.display(), err))?;
+
+        Self::parse(&content)
+            .map_err(|err| format!("Invalid config {}: {}", path.display(), err))
+    }
+
+    pub fn parse(content: &str) -> Result<Config, String> {
+        toml::from_str(content).map_err(|err| err.to_string())
+    }
+
+    /// Identity of the client, in bot mode if the profile uses a bot token
+    pub fn get_client_profile(&self, profile: &Profile) -> ClientProfile {
+        let mut client = self.client.clone();
+
+        if let Some(bot) = profile.bot {
+            client.bot = bot;
+        }
+
+        client
+    }
+
+    /// The named profile, or the default profile if any
+    pub fn get_profile(&self, name: Option<&str>) -> Result<Profile, String> {
+        match name.or(self.default_profile.as_deref()) {
+            Some(name) => self.profiles.get(name)
+                .cloned()
+                .ok_or_else(|| format!("Unknown profile {:?}", name)),
+            None => Ok(self.profiles.get("default").cloned().unwrap_or_default()),
+        }
+    }
+}
+
+impl HttpSettings {
+    pub fn get_http_config(&self) -> HttpConfig {
+        let default = HttpConfig::default();
+
+        HttpConfig {
+            connect_timeout: self.connect_timeout.map(Duration::from_secs),
+            read_timeout: match self.read_timeout {
+                Some(0) => None,
+                Some(seconds) => Some(Duration::from_secs(seconds)),
+                None => default.read_timeout,
+            },
+            http2: self.http2,
+            proxy: self.proxy.clone(),
+            ca_certificates: self.ca_certificates.iter().map(PathBuf::from).collect(),
+            pool_max_idle_per_host: self.pool_max_idle_per_host,
+            pool_idle_timeout: self.pool_idle_timeout.map(Duration::from_secs),
+            tcp_keepalive: self.tcp_keepalive.map(Duration::from_secs),
+        }
+    }
+}
+
+impl Profile {
+    /// Credentials of the pool, tokens read from `token_env`, `token_file` then `token`.
+    /// Entries without a channel use `channel_id` (`--channel-id`), then the channel of the profile.
+    pub fn get_pool_credentials(&self, channel_id: Option<u64>, env: impl Fn(&str) -> Option<String>) -> Result<Vec<Credential>, String> {
+        self.pool.iter().enumerate()
+            .map(|(index, entry)| {
+                let name = entry.name.clone().unwrap_or_else(|| (index + 1).to_string());
+
+                let token = if let Some(token_env) = &entry.token_env {
+                    env(token_env).ok_or_else(|| format!("Environment variable {} is not set", token_env))?
+                } else if let Some(token_file) = &entry.token_file {
+                    read_token_file(token_file)?
+                } else {
+                    entry.token.clone().ok_or_else(|| format!("No token for pool credential {}", name))?
+                };
+
+                let channel_id = entry.channel_id.or(channel_id).or(self.channel_id)
+                    .ok_or_else(|| format!("No channel id for pool credential {}", name))?;
+
+                Ok(Credential::named(name, token, channel_id, entry.concurrency.unwrap_or(1)))
+            })
+            .collect()
+    }
+
+    pub fn get_subscription(&self) -> Result<Option<Subscription>, String> {
+        self.subscription.as_deref().map(str::parse).transpose()
+    }
+
+    pub fn get_container_size(&self, chunk_size: u32) -> Result<Option<usize>, String> {
+        match self.container_size {
+            Some(size) => Ok(Some(size)),
+            None => Ok(self.get_subscription()?.map(|subscription| subscription.get_container_size(chunk_size))),
+        }
+    }
+}
+
+/// Pick the token from, in order: `--token`, `--token-file`,
+/// `DISCORD_US_TOKEN`, then the profile `token_env`, `token_file` and `token`
+pub fn resolve_token(token: Option<String>, token_file: Option<String>, profile: &Profile, env: impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    if let Some(token) = token {
+        return Ok(token);
+    }
+
+    if let Some(token_file) = token_file {
+        return read_token_file(&token_file);
+    }
+
+    if let Some(token) = env(TOKEN_ENV) {
+        return Ok(token);
+    }
+
+    if let Some(name) = &profile.token_env {
+        return env(name).ok_or_else(|| format!("Environment variable {} is not set", name));
+    }
+
+    if let Some(token_file) = &profile.token_file {
+        return read_token_file(token_file);
+    }
+
+    profile.token.clone()
+        .ok_or_else(|| format!("No token, use --token, --token-file, {} or a profile", TOKEN_ENV))
+}
+
+fn read_token_file(path: &str) -> Result<String, String> {
+    std::fs::read_to_string(path)
+        .map(|token| token.trim().to_string())
+        .map_err(|err| format!("Cannot read token file {}: {}", path, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use discord_us::common::DEFAULT_CHUNK_SIZE;
+    use discord_us::credentials::Credential;
+    use discord_us::http_client::{ClientProfile, HttpConfig};
+    use crate::config::{resolve_token, Config, Profile, TOKEN_ENV};
+
+    const CONFIG: &str = r#"
+default_profile = "main"
+
+[profiles.main]
+token_env = "MAIN_TOKEN"
+channel_id = 42
+subscription = "basic"
+threads = 4
+
+[profiles.other]
+token = "other-token"
+container_size = 1048576
+bot = true
+
+[[profiles.main.pool]]
+name = "alice"
+token_env = "MAIN_TOKEN"
+concurrency = 2
+
+[[profiles.main.pool]]
+token = "bob-token"
+channel_id = 7
+
+[http]
+proxy = "http://proxy:3128"
+read_timeout = 0
+
+[client]
+locale = "en-US"
+super_properties = { client_build_number = 42 }
+"#;
+
+    #[test]
+    fn test_profiles() {
+        let config = Config::parse(CONFIG).unwrap();
+
+        let main = config.get_profile(None).unwrap();
+        assert_eq!(main.channel_id, Some(42));
+        assert_eq!(main.threads, Some(4));
+        assert_eq!(main.get_container_size(DEFAULT_CHUNK_SIZE), Ok(Some(50 * 1024 * 1024)));
+        assert_eq!(main.get_container_size(4 * 1024 * 1024), Ok(Some(48 * 1024 * 1024)));
+
+        let other = config.get_profile(Some("other")).unwrap();
+        assert_eq!(other.get_container_size(DEFAULT_CHUNK_SIZE), Ok(Some(1048576)));
+
+        assert!(config.get_profile(Some("missing")).is_err());
+        assert!(Config::parse("unknown = 1").is_err());
+
+        assert_eq!(Config::default().get_profile(None), Ok(Profile::default()));
+
+        let http = config.http.get_http_config();
+        assert_eq!(http.proxy.as_deref(), Some("http://proxy:3128"));
+        assert_eq!(http.read_timeout, None);
+        assert_eq!(Config::default().http.get_http_config(), HttpConfig::default());
+
+        let client = config.get_client_profile(&main);
+        assert_eq!(client.locale, "en-US");
+        assert_eq!(client.timezone, ClientProfile::default().timezone);
+        assert_eq!(client.super_properties.client_build_number, 42);
+        assert!(!client.bot);
+        assert!(config.get_client_profile(&other).bot);
+
+        let env = |name: &str| (name == "MAIN_TOKEN").then(|| "alice-token".to_string());
+        let pool = main.get_pool_credentials(None, env).unwrap();
+        assert_eq!(pool, vec![
+            Credential::named("alice".to_string(), "alice-token".to_string(), 42, 2),
+            Credential::named("2".to_string(), "bob-token".to_string(), 7, 1),
+        ]);
+
+        // --channel-id comes before the channel of the profile, not before the one of an entry
+        let pool = main.get_pool_credentials(Some(99), env).unwrap();
+        assert_eq!((pool[0].channel_id, pool[1].channel_id), (99, 7));
+
+        assert!(main.get_pool_credentials(None, |_| None).is_err());
+        assert_eq!(other.get_pool_credentials(None, |_| None), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn test_token_precedence() {
+        let config = Config::parse(CONFIG).unwrap();
+        let main = config.get_profile(None).unwrap();
+
+        let env = |name: &str| match name {
+            "MAIN_TOKEN" => Some("main-token".to_string()),
+            _ => None,
+        };
+
+        assert_eq!(resolve_token(Some("flag".to_string()), None, &main, env), Ok("flag".to_string()));
+        assert_eq!(resolve_token(None, None, &main, env), Ok("main-token".to_string()));
+
+        // the environment overrides the profile
+        let env_with_default = |name: &str| (name == TOKEN_ENV).then(|| "env-token".to_string());
+        assert_eq!(resolve_token(None, None, &main, env_with_default), Ok("env-token".to_string()));
+        assert_eq!(resolve_token(None, None, &main, |_| None), Err("Environment variable MAIN_TOKEN is not set".to_string()));
+
+        let token_file = std::env::temp_dir().join(format!("discord-us-token-{}", rand::random::<u64>()));
+        std::fs::write(&token_file, "file-token\n").unwrap();
+
+        let token_file = token_file.to_str().unwrap().to_string();
+        assert_eq!(resolve_token(None, Some(token_file.clone()), &main, env_with_default), Ok("file-token".to_string()));
+
+        std::fs::remove_file(token_file).unwrap();
+
+        let other = config.get_profile(Some("other")).unwrap();
+        assert_eq!(resolve_token(None, None, &other, |_| None), Ok("other-token".to_string()));
+        assert!(resolve_token(None, None, &Profile::default(), |_| None).is_err());
+    }
+}
@@ -0,0 +1,141 @@
+use bytesize::ByteSize;
+use serde_json::{json, Value};
+use discord_us::catalog::CatalogEntry;
+use discord_us::common::{Container, Waterfall, METADATA_SIZE};
+use crate::utils::{format_expiry, format_timestamp};
+
+pub fn print_info(waterfall: &Waterfall) {
+    let expiries: Vec<u64> = waterfall.containers.iter().filter_map(|c| c.get_expiry()).collect();
+
+    println!("Filename:     {}", waterfall.filename);
+    println!("Size:         {} ({} bytes)", ByteSize(waterfall.size).to_string_as(true), waterfall.size);
+    println!("Containers:   {}", waterfall.containers.len());
+
+    if waterfall.is_root_pointer() {
+        println!("Root pointer: level {}, the size and hash are those of the manifest", waterfall.manifest_level);
+    }
+
+    for chunk_size in chunk_sizes(waterfall) {
+        println!("Chunk size:   {} ({} bytes of data, {} bytes of metadata)",
+                 ByteSize(chunk_size).to_string_as(true),
+                 chunk_size - METADATA_SIZE as u64,
+                 METADATA_SIZE);
+    }
+
+    println!("Encryption:   {}", encryption(waterfall));
+    println!("File hash:    {}", waterfall.file_hash.map(to_hex).unwrap_or_else(|| "none".to_string()));
+    println!("Password:     {}", if waterfall.password.is_empty() { "not embedded" } else { "embedded" });
+
+    match (expiries.iter().min(), expiries.iter().max()) {
+        (Some(first), Some(last)) => {
+            println!("Links expire: {}", format_expiry(*first));
+
+            if first != last {
+                println!("              to {}", format_expiry(*last));
+            }
+
+            if expiries.len() < waterfall.containers.len() {
+                println!("              {} links without expiry", waterfall.containers.len() - expiries.len());
+            }
+        }
+        _ => println!("Links expire: never"),
+    }
+}
+
+pub fn print_containers(waterfall: &Waterfall) {
+    println!("{:>5}  {:>12}  {:>12}  {:>6}  {:<25}  locator", "index", "start", "end", "chunks", "expires");
+
+    for container in sorted_containers(waterfall) {
+        println!("{:>5}  {:>12}  {:>12}  {:>6}  {:<25}  {}",
+                 container.index,
+                 container.bytes_range[0],
+                 container.bytes_range[1],
+                 container.chunk_count,
+                 container.get_expiry().map(format_timestamp).unwrap_or_else(|| "-".to_string()),
+                 container.storage_url);
+    }
+}
+
+pub fn to_json(waterfall: &Waterfall, containers: bool) -> Value {
+    let mut value = json!({
+        "filename": waterfall.filename,
+        "size": waterfall.size,
+        "container_count": waterfall.containers.len(),
+        "manifest_level": waterfall.manifest_level,
+        "chunk_sizes": chunk_sizes(waterfall),
+        "metadata_size": METADATA_SIZE,
+        "encryption": encryption(waterfall),
+        "file_hash": waterfall.file_hash.map(to_hex),
+        "password_embedded": !waterfall.password.is_empty(),
+        "expires_at": waterfall.get_expiry(),
+    });
+
+    if containers {
+        value["containers"] = sorted_containers(waterfall).iter()
+            .map(|container| json!({
+                "index": container.index,
+                "bytes_range": container.bytes_range,
+                "chunk_count": container.chunk_count,
+                "chunk_size": container.chunk_size,
+                "kdf_iterations": container.kdf_iterations,
+                "storage_url": container.storage_url,
+                "message_id": container.message_id,
+                "channel_id": container.channel_id,
+                "credential": container.credential,
+                "expires_at": container.get_expiry(),
+            }))
+            .collect();
+    }
+
+    value
+}
+
+pub fn print_entries(entries: &[CatalogEntry]) {
+    println!("{:>5}  {:<23}  {:>10}  {:<40}  tags", "id", "uploaded", "size", "filename");
+
+    for entry in entries {
+        println!("{:>5}  {:<23}  {:>10}  {:<40}  {}",
+                 entry.id,
+                 format_timestamp(entry.uploaded_at),
+                 ByteSize(entry.size).to_string_as(true),
+                 entry.filename,
+                 entry.tags.join(", "));
+    }
+}
+
+pub fn print_entry(entry: &CatalogEntry) {
+    println!("Id:           {}", entry.id);
+    println!("Uploaded:     {}", format_timestamp(entry.uploaded_at));
+    println!("Source:       {}", entry.source_path.as_deref().unwrap_or("unknown"));
+    println!("Waterfall:    {}", entry.waterfall_path.as_deref().unwrap_or("unknown"));
+    println!("Waterfall sha256: {}", to_hex(entry.waterfall_hash));
+    println!("Channel:      {}", entry.channel_id.map(|id| id.to_string()).unwrap_or_else(|| "unknown".to_string()));
+    println!("Tags:         {}", if entry.tags.is_empty() { "none".to_string() } else { entry.tags.join(", ") });
+}
+
+fn sorted_containers(waterfall: &Waterfall) -> Vec<&Container> {
+    let mut containers: Vec<&Container> = waterfall.containers.iter().collect();
+    containers.sort_by_key(|c| c.index);
+    containers
+}
+
+fn chunk_sizes(waterfall: &Waterfall) -> Vec<u64> {
+    let mut sizes: Vec<u64> = waterfall.containers.iter().map(|c| c.chunk_size).collect();
+    sizes.sort();
+    sizes.dedup();
+    sizes
+}
+
+fn encryption(waterfall: &Waterfall) -> String {
+    let mut iterations: Vec<u32> = waterfall.containers.iter().map(|c| c.kdf_iterations).collect();
+    iterations.sort();
+    iterations.dedup();
+
+    let iterations: Vec<String> = iterations.iter().map(|i| i.to_string()).collect();
+
+    format!("AES-256-CBC, key derived with PBKDF2-HMAC-SHA256 ({} iterations)", iterations.join("/"))
+}
+
+fn to_hex(bytes: [u8; 32]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -1,25 +1,44 @@
 mod utils;
+mod inspect;
+mod config;
 
 use std::thread::sleep;
-use std::io::{stdout, Write};
+use std::fs::File;
+use std::io::{stdout, ErrorKind, Read, Write};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use clap::{Parser, Subcommand};
 // use clap::builder::Str;
 
-use discord_us::common::{Waterfall, FileReadable, Subscription, FileWritable};
+use discord_us::cache::ChunkCache;
+use discord_us::catalog::Catalog;
+use discord_us::share::is_share_string;
+use discord_us::credentials::{Credential, CredentialPool};
+use discord_us::common::{Waterfall, FileReadable, Subscription, WaterfallFormat, DEFAULT_CHUNK_SIZE};
 use discord_us::downloader::{FileDownloader, Downloader, WaterfallDownloader, ByteRangeDownloader};
-use discord_us::signal::{PartProgression, Signal};
+use discord_us::signal::{PartProgression, Signal, TransferStats};
+use discord_us::throttle::RateLimiter;
 
 use std::time::Instant;
 
 use bytesize::ByteSize;
 use discord_us::uploader::{FileUploadArguments, FileUploader, Uploader, WaterfallExporter};
-use crate::utils::to_progress_bar;
+#[cfg(feature = "fuse")]
+use discord_us::vfs::WaterfallFs;
+use crate::config::{resolve_token, Config, DEFAULT_CACHE_SIZE};
+use crate::utils::{format_stats, to_progress_bar};
 
 #[derive(Parser, Debug)]
 #[command(name = "discord-us", version = "0.1.0", about = "Discord Unlimited Storage")]
 struct Cli {
+    /// Configuration file, $XDG_CONFIG_HOME/discord-us/config.toml by default
+    #[arg(long, global = true)]
+    config: Option<String>,
+
+    /// Profile of the configuration file to use
+    #[arg(long, global = true)]
+    profile: Option<String>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -30,11 +49,23 @@ enum Commands {
         #[arg(short, long)]
         password: Option<String>,
 
-        #[arg(short, long)]
-        waterfall: String,
+        #[arg(short, long, required_unless_present = "from_link")]
+        waterfall: Option<String>,
+
+        /// Share string (dus://...) instead of a waterfall file
+        #[arg(long, conflicts_with = "waterfall")]
+        from_link: Option<String>,
 
         #[arg(short, long)]
         output: String,
+
+        /// Maximum download rate per second (e.g. 500K, 2MiB)
+        #[arg(long)]
+        limit_rate: Option<ByteSize>,
+
+        /// Number of containers downloaded at the same time
+        #[arg(long)]
+        threads: Option<u32>,
     },
 
     Upload {
@@ -47,14 +78,184 @@ enum Commands {
         #[arg(short, long)]
         input: String,
 
-        #[arg(short, long, default_value_t = Subscription::Free.get_max_chunk_upload_size())]
-        container_size: usize,
+        /// Must be a multiple of the chunk size, defaults to the limit of the tier
+        #[arg(short, long)]
+        container_size: Option<usize>,
 
+        /// Size of the encrypted chunks, a power of two from 16KiB to 4MiB (default 64KiB)
+        #[arg(long)]
+        chunk_size: Option<ByteSize>,
+
+        /// Subscription of the account, sets the maximum container size
+        #[arg(long, value_parser = str::parse::<Subscription>)]
+        tier: Option<Subscription>,
+
+        /// Number of containers uploaded at the same time
+        #[arg(long)]
+        threads: Option<u32>,
+
+        /// Prefer --token-file or DISCORD_US_TOKEN, arguments are visible to other users
         #[arg(short, long)]
-        token: String,
+        token: Option<String>,
 
+        /// File holding the token
         #[arg(long)]
-        channel_id: u64,
+        token_file: Option<String>,
+
+        #[arg(long)]
+        channel_id: Option<u64>,
+
+        /// Post the containers with this channel webhook instead of a token,
+        /// prefer a profile, arguments are visible to other users
+        #[arg(long, conflicts_with_all = ["token", "token_file", "channel_id"])]
+        webhook: Option<String>,
+
+        /// PBKDF2 iterations used to derive the container keys
+        #[arg(long)]
+        kdf_iterations: Option<u32>,
+
+        /// Maximum upload rate per second (e.g. 500K, 2MiB)
+        #[arg(long)]
+        limit_rate: Option<ByteSize>,
+
+        /// Encoding of the waterfall, json or binary
+        #[arg(long, default_value = "json", value_parser = str::parse::<WaterfallFormat>)]
+        format: WaterfallFormat,
+
+        /// Also upload the waterfall, and write a root pointer to it instead
+        #[arg(long)]
+        root_pointer: bool,
+
+        /// Tag of the upload in the catalog, can be repeated
+        #[arg(long)]
+        tag: Vec<String>,
+
+        /// Do not record the upload in the catalog
+        #[arg(long)]
+        no_catalog: bool,
+    },
+
+    /// List the uploads of the catalog
+    List,
+
+    /// Find uploads of the catalog by filename, path or tag
+    Search {
+        /// Part of the filename, source path or waterfall path
+        text: Option<String>,
+
+        /// Required tag, can be repeated
+        #[arg(long)]
+        tag: Vec<String>,
+    },
+
+    /// Print an upload of the catalog
+    Show {
+        id: i64,
+
+        /// Write the waterfall of the upload into this file
+        #[arg(long)]
+        export: Option<String>,
+
+        /// Encoding of the exported waterfall, json or binary
+        #[arg(long, default_value = "json", value_parser = str::parse::<WaterfallFormat>)]
+        format: WaterfallFormat,
+    },
+
+    /// Remove an upload from the catalog, its containers are kept
+    Forget {
+        id: i64,
+    },
+
+    /// Print a share string (dus://...) of a waterfall, usable in place of the waterfall file
+    Share {
+        waterfall: String,
+
+        /// Password to embed, instead of the password of the waterfall
+        #[arg(short, long)]
+        password: Option<String>,
+
+        /// Leave the password out of the share string
+        #[arg(long, conflicts_with = "password")]
+        no_password: bool,
+    },
+
+    /// Convert a waterfall between the json and binary encodings
+    Convert {
+        input: String,
+
+        output: String,
+
+        /// Encoding of the output, the other encoding than the input by default
+        #[arg(long, value_parser = str::parse::<WaterfallFormat>)]
+        format: Option<WaterfallFormat>,
+    },
+
+    /// Print a summary of a waterfall
+    Info {
+        waterfall: String,
+    },
+
+    /// Print the details of a waterfall
+    Inspect {
+        waterfall: String,
+
+        /// List every container
+        #[arg(long)]
+        containers: bool,
+
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Write a whole file to stdout
+    Cat {
+        waterfall: String,
+
+        #[arg(short, long)]
+        password: Option<String>,
+    },
+
+    /// Write a byte range of a file, only downloading the chunks holding it
+    Extract {
+        waterfall: String,
+
+        #[arg(short, long)]
+        password: Option<String>,
+
+        /// First byte of the range (e.g. 4096, 1MiB)
+        #[arg(long, conflicts_with = "range")]
+        offset: Option<ByteSize>,
+
+        /// Number of bytes, up to the end of the file by default
+        #[arg(long, conflicts_with = "range")]
+        length: Option<ByteSize>,
+
+        /// Byte range as START-END, END is excluded and optional (e.g. 1MiB-5MiB)
+        #[arg(long, value_parser = utils::parse_range)]
+        range: Option<(u64, Option<u64>)>,
+
+        /// Output file, stdout by default
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+
+    /// Mount a directory of waterfalls, or the catalog, as read-only files
+    /// until unmounted with `fusermount -u`
+    #[cfg(feature = "fuse")]
+    Mount {
+        mountpoint: String,
+
+        /// Directory of waterfall files
+        #[arg(long, required_unless_present = "catalog", conflicts_with = "catalog")]
+        directory: Option<String>,
+
+        /// Mount the uploads of the catalog
+        #[arg(long)]
+        catalog: bool,
+
+        /// Password of the waterfalls without their own
+        #[arg(short, long)]
+        password: Option<String>,
     },
 }
 
@@ -62,58 +263,200 @@ fn main() {
     let args = Cli::parse();
 
     match args.command {
-        Commands::Download { password, waterfall, output } => {
-            let mut signal: PartProgression<u64> = PartProgression::new();
+        Commands::Download { password, waterfall, from_link, output, limit_rate, threads } => {
+            let config = match Config::load(args.config) {
+                Ok(config) => config,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    return;
+                }
+            };
 
-            let waterfall = Waterfall::from_file(waterfall);
+            let profile = match config.get_profile(args.profile.as_deref()) {
+                Ok(profile) => profile,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    return;
+                }
+            };
 
-            println!("Downloading file {} ({}) into {}", waterfall.filename, ByteSize(waterfall.size).to_string_as(true), output);
+            let client = match config.http.get_http_config().build() {
+                Ok(client) => client,
+                Err(err) => {
+                    eprintln!("Invalid http settings: {}", err);
+                    return;
+                }
+            };
+
+            let signal: PartProgression<u64> = PartProgression::new();
+
+            let waterfall = match from_link.or(waterfall).map(|source| load_waterfall(&source)) {
+                Some(Ok(waterfall)) => waterfall,
+                Some(Err(err)) => {
+                    eprintln!("Invalid waterfall: {}", err);
+                    return;
+                }
+                None => return,
+            };
 
-            let mut file_downloader = FileDownloader::from_waterfall(waterfall.clone());
+            let mut file_downloader = FileDownloader::from_waterfall(waterfall);
             let now = Instant::now();
 
             if let Some(password) = password {
                 file_downloader.set_password(password);
             }
 
+            file_downloader.with_client(&client);
+
+            if let Err(err) = resolve_root_pointer(&mut file_downloader) {
+                eprintln!("Cannot resolve the root pointer: {}", err);
+                return;
+            }
+
+            let waterfall = file_downloader.get_waterfall().clone();
+
+            println!("Downloading file {} ({}) into {}", waterfall.filename, ByteSize(waterfall.size).to_string_as(true), output);
+
             file_downloader.with_signal(&signal);
 
+            let stats = TransferStats::new(waterfall.size);
+            file_downloader.with_events(&stats);
+
+            if let Some(limit_rate) = limit_rate {
+                file_downloader.with_rate_limiter(&RateLimiter::new(limit_rate.as_u64()));
+            }
+
+            if let Some(threads) = threads.or(profile.threads) {
+                file_downloader.with_threads(threads as usize);
+            }
+
             let f = Arc::new(Mutex::new(file_downloader.clone()));
 
             let handle = thread::spawn(move || {
-                f.lock().unwrap().download_file(output);
+                f.lock().unwrap().download_file(output)
             });
 
 
-            while signal.get_total() != file_downloader.get_size() {
+            while signal.get_total() != file_downloader.get_size() && !handle.is_finished() {
                 sleep(std::time::Duration::from_millis(50));
 
-                signal.retrim_ranges();
-
                 let progress = signal.get_total();
                 let data = signal.get_data();
 
-                let elapsed = now.elapsed().as_secs_f64();
-
                 let bar = to_progress_bar(data, file_downloader.get_size(), 50, '#', '-');
 
-                print!("\rProgress: {} {}/{} ({}/s) ({:.2}%)",
+                print!("\rProgress: {} {} ({:.2}%)   ",
                        bar,
-                       ByteSize(progress).to_string_as(true),
-                       ByteSize(file_downloader.get_size()).to_string_as(true),
-                       ByteSize((progress as f64 / elapsed) as u64).to_string_as(true),
+                       format_stats(&stats.snapshot()),
                        (progress as f64 / file_downloader.get_size() as f64) * 100.0);
 
                 stdout().flush().unwrap();
             }
-            handle.join().unwrap();
+            if let Err(err) = handle.join().unwrap() {
+                eprintln!("\nDownload failed: {}", err);
+                return;
+            }
 
             println!("\nDownloaded succeed {:?}", now.elapsed());
         }
-        Commands::Upload { input, password, waterfall, container_size, channel_id, token } => {
-            let mut signal: PartProgression<u64> = PartProgression::new();
+        Commands::Upload { input, password, waterfall, container_size, chunk_size, tier, threads, channel_id, token, token_file, webhook, kdf_iterations, limit_rate, format, root_pointer, tag, no_catalog } => {
+            let config = Config::load(args.config);
+
+            let profile = match config.as_ref().map_err(|err| err.clone()).and_then(|config| config.get_profile(args.profile.as_deref())) {
+                Ok(profile) => profile,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    return;
+                }
+            };
+
+            let webhook = webhook.or(profile.webhook.clone());
+
+            // a webhook needs neither a token nor a channel,
+            // the pool of the profile is used unless a token is given
+            let (credentials, channel_id) = match webhook {
+                Some(_) => (CredentialPool::new(Vec::new()), None),
+                None if token.is_none() && token_file.is_none() && !profile.pool.is_empty() => {
+                    match profile.get_pool_credentials(channel_id, |name| std::env::var(name).ok()) {
+                        Ok(credentials) => (CredentialPool::new(credentials), channel_id.or(profile.channel_id)),
+                        Err(err) => {
+                            eprintln!("{}", err);
+                            return;
+                        }
+                    }
+                }
+                None => {
+                    let token = match resolve_token(token, token_file, &profile, |name| std::env::var(name).ok()) {
+                        Ok(token) => token,
+                        Err(err) => {
+                            eprintln!("{}", err);
+                            return;
+                        }
+                    };
+
+                    let Some(channel_id) = channel_id.or(profile.channel_id) else {
+                        eprintln!("No channel id, use --channel-id, --webhook or a profile");
+                        return;
+                    };
+
+                    (CredentialPool::new(vec![Credential::new(token, channel_id)]), Some(channel_id))
+                }
+            };
+
+            let chunk_size = chunk_size.map(|size| size.as_u64() as u32)
+                .or(profile.chunk_size)
+                .unwrap_or(DEFAULT_CHUNK_SIZE);
+
+            if let Err(err) = FileUploader::check_chunk_size(chunk_size) {
+                eprintln!("{}", err);
+                return;
+            }
+
+            let (profile_size, profile_tier) = match (profile.get_container_size(chunk_size), profile.get_subscription()) {
+                (Ok(size), Ok(tier)) => (size, tier),
+                (Err(err), _) | (_, Err(err)) => {
+                    eprintln!("{}", err);
+                    return;
+                }
+            };
+
+            let requested_size = container_size;
 
-            let mut file_uploader = FileUploader::new(input, container_size as u32);
+            // flags first, then the profile
+            let mut container_size = container_size
+                .or(tier.map(|tier| tier.get_container_size(chunk_size)))
+                .or(profile_size)
+                .unwrap_or(Subscription::Free.get_container_size(chunk_size));
+
+            // webhook attachments have the limit of an account without subscription,
+            // larger sizes coming from the tier or the profile are lowered
+            if webhook.is_some() {
+                let webhook_size = Subscription::Free.get_container_size(chunk_size);
+
+                if let Some(Err(err)) = requested_size.map(|size| FileUploader::check_webhook_container_size(size as u64)) {
+                    eprintln!("{}", err);
+                    return;
+                }
+
+                container_size = container_size.min(webhook_size);
+            }
+
+            if let Err(err) = FileUploader::check_container_size(container_size as u64, chunk_size) {
+                eprintln!("{}", err);
+                return;
+            }
+
+            if let Some(tier) = tier.or(profile_tier) {
+                if container_size > tier.get_max_chunk_upload_size() {
+                    eprintln!("Container size {} is above the {:?} limit of {}", container_size, tier, tier.get_max_chunk_upload_size());
+                    return;
+                }
+            }
+
+            let signal: PartProgression<u64> = PartProgression::new();
+
+            let threads = threads.or(profile.threads).unwrap_or(2).max(1);
+            let mut file_uploader = FileUploader::new_with_chunk_size(input.clone(), container_size as u32, threads, chunk_size);
             let now = Instant::now();
 
             let pass = match password.clone() {
@@ -121,56 +464,464 @@ fn main() {
                 Some(pass) => pass
             };
 
-            let mut upload_args = FileUploadArguments::new(pass.clone(), token.clone(), channel_id);
+            let (client, client_profile) = match config.as_ref().map_err(|err| err.clone()).and_then(|config| {
+                let client = config.http.get_http_config().build().map_err(|err| format!("Invalid http settings: {}", err))?;
+
+                Ok((client, config.get_client_profile(&profile)))
+            }) {
+                Ok(client) => client,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    return;
+                }
+            };
+
+            let mut upload_args = match webhook {
+                Some(webhook) => FileUploadArguments::new_webhook(pass.clone(), webhook),
+                None => FileUploadArguments::new_pool(pass.clone(), &credentials),
+            };
+            upload_args.with_client(&client);
+            upload_args.with_client_profile(&client_profile);
+
+            if let Some(kdf_iterations) = kdf_iterations.or(profile.kdf_iterations) {
+                upload_args.with_kdf_iterations(kdf_iterations);
+            }
+
+            if let Some(limit_rate) = limit_rate {
+                upload_args.with_rate_limiter(&RateLimiter::new(limit_rate.as_u64()));
+            }
+
+            let manifest_args = upload_args.clone();
+
+            // progress in bytes of the input file, comparable with the download progress
+            upload_args.with_source_signal(&signal);
 
-            upload_args.with_signal(&signal);
+            let stats = TransferStats::new(file_uploader.get_file_size());
+            upload_args.with_events(&stats);
 
-            let total_upload_size = file_uploader.upload(upload_args);
+            let handle = file_uploader.upload(upload_args);
 
-            let start = Instant::now();
+            let file_size = handle.get_file_size();
 
-            println!("\n");
+            println!("Uploading file ({} encoded as {})\n",
+                     ByteSize(file_size).to_string_as(true),
+                     ByteSize(handle.get_total_size()).to_string_as(true));
 
-            loop {
+            let results = loop {
                 sleep(std::time::Duration::from_millis(50));
 
-                signal.retrim_ranges();
+                let results = handle.try_wait();
 
                 let progress = signal.get_total();
                 let data = signal.get_data();
 
-                let elapsed = start.elapsed().as_secs_f64();
+                let bar = to_progress_bar(data, file_size, 50, '#', '-');
 
-                let bar = to_progress_bar(data, total_upload_size, 50, '#', '-');
-
-                print!("\rProgress: {} {}/{} ({}/s) ({:.2}%)",
+                print!("\rProgress: {} {} ({:.2}%)   ",
                          bar,
-                         ByteSize(progress).to_string_as(true),
-                         ByteSize(total_upload_size).to_string_as(true),
-                         ByteSize((progress as f64 / elapsed) as u64).to_string_as(true),
-                         (progress as f64 / total_upload_size as f64) * 100.0);
+                         format_stats(&stats.snapshot()),
+                         (progress as f64 / file_size as f64) * 100.0);
 
                 stdout().flush().unwrap();
 
-                if progress == total_upload_size {
-                    println!("\nFinalizing upload...");
-                    sleep(std::time::Duration::from_millis(5000));
-                    break;
+                if let Some(results) = results {
+                    break results;
+                }
+            };
+
+            println!();
+
+            // a single token shows up in the container errors
+            for status in credentials.get_status().into_iter().filter(|status| !status.name.is_empty()) {
+                if let Some(reason) = status.disabled {
+                    eprintln!("Credential {} disabled: {}", status.name, reason);
                 }
             }
 
+            let mut failed = false;
+
+            for result in results.iter() {
+                if let Err(err) = &result.result {
+                    eprintln!("Container {} failed: {}", result.index, err);
+                    failed = true;
+                }
+            }
+
+            if failed {
+                return;
+            }
+
             let waterfall_struct = if password.is_some() {
                 file_uploader.export_waterfall()
             } else {
                 file_uploader.export_waterfall_with_password(pass.clone())
             };
 
+            let mut waterfall_struct = match waterfall_struct {
+                Ok(waterfall_struct) => waterfall_struct,
+                Err(err) => {
+                    eprintln!("Cannot export waterfall: {}", err);
+                    return;
+                }
+            };
+
+            if root_pointer {
+                println!("Uploading waterfall ({} containers)", waterfall_struct.containers.len());
+
+                waterfall_struct = match FileUploader::upload_root_pointer(&waterfall_struct, container_size as u32, chunk_size, manifest_args) {
+                    Ok(root) => root,
+                    Err(err) => {
+                        eprintln!("Cannot upload waterfall: {}", err);
+                        return;
+                    }
+                };
+            }
+
 
             println!("Exporting waterfall");
 
-            waterfall_struct.write_to_file(waterfall.clone());
+            if let Err(err) = waterfall_struct.write_to_file_as(waterfall.clone(), format) {
+                eprintln!("Cannot write waterfall {}: {}", waterfall, err);
+                return;
+            }
+
+            if !no_catalog {
+                let registered = config.and_then(|config| open_catalog(&config)).and_then(|mut catalog| {
+                    catalog.register(&waterfall_struct, Some(absolute_path(&input)), Some(absolute_path(&waterfall)), channel_id, &tag)
+                        .map_err(|err| err.to_string())
+                });
+
+                match registered {
+                    Ok(id) => println!("Recorded in the catalog as {}", id),
+                    Err(err) => eprintln!("Cannot record the upload in the catalog: {}", err),
+                }
+            }
 
             println!("Uploaded succeed {:?}", now.elapsed());
         }
+        Commands::List => {
+            match Config::load(args.config).and_then(|config| open_catalog(&config)).and_then(|catalog| catalog.list().map_err(|err| err.to_string())) {
+                Ok(entries) => inspect::print_entries(&entries),
+                Err(err) => eprintln!("{}", err),
+            }
+        }
+        Commands::Search { text, tag } => {
+            match Config::load(args.config).and_then(|config| open_catalog(&config)).and_then(|catalog| catalog.search(text.as_deref(), &tag).map_err(|err| err.to_string())) {
+                Ok(entries) => inspect::print_entries(&entries),
+                Err(err) => eprintln!("{}", err),
+            }
+        }
+        Commands::Show { id, export, format } => {
+            let catalog = match Config::load(args.config).and_then(|config| open_catalog(&config)) {
+                Ok(catalog) => catalog,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    return;
+                }
+            };
+
+            let (entry, waterfall) = match (catalog.get(id), catalog.get_waterfall(id)) {
+                (Ok(Some(entry)), Ok(Some(waterfall))) => (entry, waterfall),
+                (Err(err), _) | (_, Err(err)) => {
+                    eprintln!("Cannot read the catalog: {}", err);
+                    return;
+                }
+                _ => {
+                    eprintln!("No upload {} in the catalog", id);
+                    return;
+                }
+            };
+
+            inspect::print_entry(&entry);
+            inspect::print_info(&waterfall);
+
+            if let Some(export) = export {
+                if let Err(err) = waterfall.write_to_file_as(export.clone(), format) {
+                    eprintln!("Cannot write waterfall {}: {}", export, err);
+                }
+            }
+        }
+        Commands::Forget { id } => {
+            match Config::load(args.config).and_then(|config| open_catalog(&config)).and_then(|mut catalog| catalog.forget(id).map_err(|err| err.to_string())) {
+                Ok(true) => println!("Forgot upload {}", id),
+                Ok(false) => eprintln!("No upload {} in the catalog", id),
+                Err(err) => eprintln!("{}", err),
+            }
+        }
+        Commands::Share { waterfall, password, no_password } => {
+            let mut waterfall = match load_waterfall(&waterfall) {
+                Ok(waterfall) => waterfall,
+                Err(err) => {
+                    eprintln!("Invalid waterfall: {}", err);
+                    return;
+                }
+            };
+
+            if let Some(password) = password {
+                waterfall.password = password;
+            }
+
+            if !no_password && waterfall.password.is_empty() {
+                eprintln!("The waterfall has no password, use --password to embed it");
+            }
+
+            match waterfall.to_share_string(!no_password) {
+                Ok(share) => println!("{}", share),
+                Err(err) => eprintln!("Cannot encode the waterfall: {}", err),
+            }
+        }
+        Commands::Convert { input, output, format } => {
+            let bytes = match std::fs::read(&input) {
+                Ok(bytes) => bytes,
+                Err(err) => {
+                    eprintln!("Cannot read {}: {}", input, err);
+                    return;
+                }
+            };
+
+            let waterfall = match Waterfall::from_bytes(&bytes) {
+                Ok(waterfall) => waterfall,
+                Err(err) => {
+                    eprintln!("Invalid waterfall: {}", err);
+                    return;
+                }
+            };
+
+            let format = format.unwrap_or(match WaterfallFormat::detect(&bytes) {
+                WaterfallFormat::Json => WaterfallFormat::Binary,
+                WaterfallFormat::Binary => WaterfallFormat::Json,
+            });
+
+            if let Err(err) = waterfall.write_to_file_as(output.clone(), format) {
+                eprintln!("Cannot write waterfall {}: {}", output, err);
+                return;
+            }
+
+            let size = std::fs::metadata(&output).map(|m| m.len()).unwrap_or(0);
+            println!("Wrote {:?} waterfall ({} -> {} bytes)", format, bytes.len(), size);
+        }
+        Commands::Info { waterfall } => {
+            let waterfall = match load_waterfall(&waterfall) {
+                Ok(waterfall) => waterfall,
+                Err(err) => {
+                    eprintln!("Invalid waterfall: {}", err);
+                    return;
+                }
+            };
+
+            inspect::print_info(&waterfall);
+        }
+        Commands::Inspect { waterfall, containers, json } => {
+            let waterfall = match load_waterfall(&waterfall) {
+                Ok(waterfall) => waterfall,
+                Err(err) => {
+                    eprintln!("Invalid waterfall: {}", err);
+                    return;
+                }
+            };
+
+            if json {
+                println!("{}", serde_json::to_string_pretty(&inspect::to_json(&waterfall, containers)).unwrap());
+                return;
+            }
+
+            inspect::print_info(&waterfall);
+
+            if containers {
+                println!();
+                inspect::print_containers(&waterfall);
+            }
+        }
+        Commands::Cat { waterfall, password } => {
+            let Some(file_downloader) = open_downloader(waterfall, password, args.config) else {
+                return;
+            };
+
+            if let Err(err) = write_range(&file_downloader, 0, file_downloader.get_size(), None) {
+                eprintln!("Download failed: {}", err);
+            }
+        }
+        Commands::Extract { waterfall, password, offset, length, range, output } => {
+            let Some(file_downloader) = open_downloader(waterfall, password, args.config) else {
+                return;
+            };
+
+            let size = file_downloader.get_size();
+
+            let offset = offset.map(|offset| offset.as_u64());
+            let length = length.map(|length| length.as_u64());
+
+            let (start, end) = match utils::resolve_range(size, offset, length, range) {
+                Ok(range) => range,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    return;
+                }
+            };
+
+            if let Err(err) = write_range(&file_downloader, start, end, output) {
+                eprintln!("Download failed: {}", err);
+            }
+        }
+        #[cfg(feature = "fuse")]
+        // --catalog is implied without --directory
+        Commands::Mount { mountpoint, directory, catalog: _, password } => {
+            let config = match Config::load(args.config) {
+                Ok(config) => config,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    return;
+                }
+            };
+
+            let fs = match directory {
+                Some(directory) => WaterfallFs::from_directory(&directory, password)
+                    .map_err(|err| format!("Cannot read directory {}: {}", directory, err)),
+                None => open_catalog(&config)
+                    .and_then(|catalog| WaterfallFs::from_catalog(&catalog, password).map_err(|err| err.to_string())),
+            };
+
+            let mut fs = match fs {
+                Ok(fs) => fs,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    return;
+                }
+            };
+
+            for skipped in fs.get_skipped() {
+                eprintln!("Skipped {}: {}", skipped.source, skipped.error);
+            }
+
+            if let Some(cache) = open_chunk_cache(&config) {
+                fs.with_chunk_cache(&cache);
+            }
+
+            println!("Mounted {} files on {}, unmount with fusermount -u {}", fs.list().len(), mountpoint, mountpoint);
+
+            if let Err(err) = discord_us::fuse::mount(fs, &mountpoint) {
+                eprintln!("Cannot mount {}: {}", mountpoint, err);
+            }
+        }
+    };
+}
+
+fn open_catalog(config: &Config) -> Result<Catalog, String> {
+    let path = config.catalog_path()
+        .ok_or_else(|| "No catalog path, set catalog in the configuration".to_string())?;
+
+    Catalog::open(&path).map_err(|err| format!("Cannot open catalog {}: {}", path.display(), err))
+}
+
+fn absolute_path(path: &str) -> String {
+    std::fs::canonicalize(path)
+        .map(|path| path.to_string_lossy().to_string())
+        .unwrap_or_else(|_| path.to_string())
+}
+
+/// Read a waterfall file, or decode a share string
+fn load_waterfall(source: &str) -> std::io::Result<Waterfall> {
+    if is_share_string(source) {
+        Waterfall::from_share_string(source)
+    } else {
+        Waterfall::from_file(source.to_string())
+    }
+}
+
+fn open_downloader(waterfall: String, password: Option<String>, config: Option<String>) -> Option<FileDownloader> {
+    let config = match Config::load(config) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            return None;
+        }
+    };
+
+    let waterfall = match load_waterfall(&waterfall) {
+        Ok(waterfall) => waterfall,
+        Err(err) => {
+            eprintln!("Invalid waterfall: {}", err);
+            return None;
+        }
+    };
+
+    let mut file_downloader = FileDownloader::from_waterfall(waterfall);
+
+    if let Some(password) = password {
+        file_downloader.set_password(password);
+    }
+
+    match config.http.get_http_config().build() {
+        Ok(client) => file_downloader.with_client(&client),
+        Err(err) => {
+            eprintln!("Invalid http settings: {}", err);
+            return None;
+        }
+    }
+
+    if let Some(cache) = open_chunk_cache(&config) {
+        file_downloader.with_cache(&cache);
+    }
+
+    if let Err(err) = resolve_root_pointer(&mut file_downloader) {
+        eprintln!("Cannot resolve the root pointer: {}", err);
+        return None;
+    }
+
+    Some(file_downloader)
+}
+
+/// Chunk cache of the configuration, `None` when disabled or unusable
+fn open_chunk_cache(config: &Config) -> Option<ChunkCache> {
+    let cache_size = config.cache_size.unwrap_or(DEFAULT_CACHE_SIZE);
+    let cache_dir = config.cache_dir().filter(|_| cache_size > 0)?;
+
+    match ChunkCache::open(&cache_dir, cache_size) {
+        Ok(cache) => Some(cache),
+        Err(err) => {
+            eprintln!("Cannot open the chunk cache {}: {}", cache_dir.display(), err);
+            None
+        }
+    }
+}
+
+fn resolve_root_pointer(file_downloader: &mut FileDownloader) -> std::io::Result<()> {
+    let level = file_downloader.get_waterfall().manifest_level;
+
+    if level > 0 {
+        eprintln!("Resolving root pointer ({} levels)", level);
+    }
+
+    file_downloader.resolve_root_pointer()
+}
+
+/// Write `[start, end)` of the file into `output`, or stdout
+fn write_range(file_downloader: &FileDownloader, start: u64, end: u64, output: Option<String>) -> std::io::Result<()> {
+    let mut range = file_downloader.get_range(start, end);
+
+    let mut writer: Box<dyn Write> = match output {
+        Some(output) => Box::new(File::create(output)?),
+        None => Box::new(stdout().lock()),
     };
+
+    let mut buf = vec![0u8; 1 << 16];
+
+    loop {
+        let read = range.read(&mut buf)?;
+
+        if read == 0 {
+            break;
+        }
+
+        match writer.write_all(&buf[..read]) {
+            // the reader of stdout is gone, e.g. `cat | head`
+            Err(err) if err.kind() == ErrorKind::BrokenPipe => return Ok(()),
+            result => result?,
+        }
+    }
+
+    match writer.flush() {
+        Err(err) if err.kind() == ErrorKind::BrokenPipe => Ok(()),
+        result => result,
+    }
 }
\ No newline at end of file
@@ -1,5 +1,7 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use bytesize::ByteSize;
 use rand::{distributions::Alphanumeric, Rng};
-use discord_us::signal::{ProgressionRange};
+use discord_us::signal::{ProgressionRange, StatsSnapshot};
 
 pub fn create_random_password(length: usize) -> String {
     rand::thread_rng()
@@ -43,10 +45,115 @@ pub fn to_progress_bar(
     result
 }
 
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+
+    if seconds >= 3600 {
+        format!("{}h{:02}m{:02}s", seconds / 3600, (seconds / 60) % 60, seconds % 60)
+    } else if seconds >= 60 {
+        format!("{}m{:02}s", seconds / 60, seconds % 60)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+/// Parse `START-END` into a start and an optional excluded end,
+/// both accepting sizes such as `4096` or `1MiB`
+pub fn parse_range(value: &str) -> Result<(u64, Option<u64>), String> {
+    let (start, end) = value.split_once('-')
+        .ok_or_else(|| format!("expected START-END, got {:?}", value))?;
+
+    let start = start.trim().parse::<ByteSize>()?.as_u64();
+
+    let end = match end.trim() {
+        "" => None,
+        end => Some(end.parse::<ByteSize>()?.as_u64()),
+    };
+
+    if let Some(end) = end {
+        if end < start {
+            return Err(format!("range end {} is before its start {}", end, start));
+        }
+    }
+
+    Ok((start, end))
+}
+
+/// `[start, end)` of a file of `size` bytes, from `--range` or `--offset` and `--length`.
+/// A length running past the end of the file is clamped, like range reads.
+pub fn resolve_range(size: u64, offset: Option<u64>, length: Option<u64>, range: Option<(u64, Option<u64>)>) -> Result<(u64, u64), String> {
+    let (start, end) = match range {
+        Some((start, end)) => (start, end.unwrap_or(size)),
+        None => {
+            let start = offset.unwrap_or(0);
+            (start, length.map(|length| start.saturating_add(length).min(size)).unwrap_or(size))
+        }
+    };
+
+    // an empty range at the end of the file is valid, and the only one of an empty file
+    if start > size || end > size || start > end {
+        return Err(format!("Range {}-{} is out of the file ({} bytes)", start, end, size));
+    }
+
+    Ok((start, end))
+}
+
+/// Unix timestamp as `YYYY-MM-DD HH:MM:SS UTC`
+pub fn format_timestamp(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let seconds = timestamp % 86400;
+
+    // civil date from days since 1970-01-01
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, seconds / 3600, (seconds / 60) % 60, seconds % 60)
+}
+
+/// Timestamp followed by the time left before it
+pub fn format_expiry(timestamp: u64) -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+
+    if timestamp <= now {
+        format!("{} (expired)", format_timestamp(timestamp))
+    } else {
+        format!("{} (in {})", format_timestamp(timestamp), format_duration(Duration::from_secs(timestamp - now)))
+    }
+}
+
+/// Progress line printed after the progress bar
+pub fn format_stats(stats: &StatsSnapshot) -> String {
+    let eta = match stats.eta {
+        Some(eta) => format_duration(eta),
+        None => "--".to_string(),
+    };
+
+    let mut line = format!("{}/{} ({}/s, ETA {}, {} workers)",
+                           ByteSize(stats.plain_bytes).to_string_as(true),
+                           ByteSize(stats.total_bytes).to_string_as(true),
+                           ByteSize(stats.rate as u64).to_string_as(true),
+                           eta,
+                           stats.workers.iter().filter(|worker| worker.rate > 0.0).count());
+
+    if stats.retries > 0 {
+        line.push_str(&format!(" ({} retries)", stats.retries));
+    }
+
+    line
+}
+
 // Do test
 #[cfg(test)]
 mod tests {
-    use crate::utils::to_progress_bar;
+    use std::time::Duration;
+    use crate::utils::{format_duration, format_timestamp, parse_range, resolve_range, to_progress_bar};
     use discord_us::signal::{ProgressionRange};
 
     #[test]
@@ -61,4 +168,39 @@ mod tests {
 
         println!("{}", result);
     }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_secs(42)), "42s");
+        assert_eq!(format_duration(Duration::from_secs(185)), "3m05s");
+        assert_eq!(format_duration(Duration::from_secs(3725)), "1h02m05s");
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_timestamp(0x65a1b2c3), "2024-01-12 21:44:35 UTC");
+        assert_eq!(format_timestamp(951782400), "2000-02-29 00:00:00 UTC");
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("100-200"), Ok((100, Some(200))));
+        assert_eq!(parse_range("1MiB-5MiB"), Ok((1 << 20, Some(5 << 20))));
+        assert_eq!(parse_range("4KiB-"), Ok((4096, None)));
+        assert!(parse_range("200-100").is_err());
+        assert!(parse_range("100").is_err());
+    }
+
+    #[test]
+    fn test_resolve_range() {
+        assert_eq!(resolve_range(100, None, None, None), Ok((0, 100)));
+        assert_eq!(resolve_range(100, Some(10), Some(20), None), Ok((10, 30)));
+        assert_eq!(resolve_range(100, Some(90), Some(u64::MAX), None), Ok((90, 100)));
+        assert_eq!(resolve_range(100, None, None, Some((20, None))), Ok((20, 100)));
+        assert_eq!(resolve_range(100, Some(100), None, None), Ok((100, 100)));
+        assert_eq!(resolve_range(0, None, None, None), Ok((0, 0)));
+        assert!(resolve_range(100, Some(101), None, None).is_err());
+        assert!(resolve_range(100, None, None, Some((20, Some(101)))).is_err());
+    }
 }
@@ -0,0 +1,563 @@
+use std::cmp::min;
+use std::collections::{HashMap, VecDeque};
+use std::io::SeekFrom;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use bytes::Bytes;
+use futures_util::stream::{self, BoxStream};
+use futures_util::{StreamExt, TryStreamExt};
+use reqwest::{Client, StatusCode};
+use sha2::{Digest, Sha256};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWriteExt, ReadBuf};
+use crate::chunk::{chunk_content_size, content_chunk_count, decrypt_chunk, derive_key_async};
+use crate::common::{Container, Waterfall, METADATA_SIZE};
+use crate::downloader::{check_manifest_size, parse_manifest, ByteRangeDownloader, WaterfallDownloader, DOWNLOAD_RETRIES};
+use crate::signal::{SignalStream, StreamSignal, TransferEvent};
+
+// Async downloader
+// Counterpart of `FileDownloader` for tokio. Each container is read through
+// a single ranged request and decrypted chunk by chunk as the bytes arrive.
+
+#[derive(Clone)]
+pub struct AsyncDownloader {
+    waterfall: Waterfall,
+    password: String,
+
+    client: Client,
+    concurrency: usize,
+    events: StreamSignal<TransferEvent>,
+    keys: KeyCache,
+}
+
+/// Salt and KDF iterations of a container
+type KeyId = ([u8; 16], u32);
+
+/// Keys of the containers, derived once per salt and iteration count
+#[derive(Clone, Default)]
+struct KeyCache {
+    keys: Arc<Mutex<HashMap<KeyId, [u8; 32]>>>,
+}
+
+impl KeyCache {
+    async fn get(&self, password: &str, container: &Container) -> std::io::Result<[u8; 32]> {
+        let id: KeyId = (container.salt, container.kdf_iterations);
+
+        if let Some(key) = self.keys.lock().unwrap().get(&id) {
+            return Ok(*key);
+        }
+
+        let key = derive_key_async(password.to_string(), container.salt, container.kdf_iterations).await?;
+        self.keys.lock().unwrap().insert(id, key);
+
+        Ok(key)
+    }
+}
+
+impl WaterfallDownloader for AsyncDownloader {
+    fn from_waterfall(waterfall: Waterfall) -> Self {
+        let password = waterfall.password.clone();
+
+        AsyncDownloader {
+            waterfall,
+            password,
+            client: Client::new(),
+            concurrency: 1,
+            events: StreamSignal::default(),
+            keys: KeyCache::default(),
+        }
+    }
+}
+
+impl ByteRangeDownloader for AsyncDownloader {
+    fn get_size(&self) -> u64 {
+        self.waterfall.size
+    }
+}
+
+impl AsyncDownloader {
+    pub fn set_password(&mut self, password: String) -> &mut AsyncDownloader {
+        self.password = password;
+        self.keys = KeyCache::default();
+
+        self
+    }
+
+    /// Number of containers downloaded at the same time, 1 by default
+    pub fn with_concurrency(&mut self, concurrency: usize) {
+        self.concurrency = concurrency.max(1);
+    }
+
+    /// Client shared by the transfers, see `HttpConfig::build_async`
+    pub fn with_client(&mut self, client: &Client) {
+        self.client = client.clone();
+    }
+
+    /// Events reported from now on, `worker` is the index of the
+    /// concurrent download, 0 for readers. A stream that falls behind
+    /// loses the oldest events.
+    pub fn events(&mut self) -> SignalStream<TransferEvent> {
+        self.events.subscribe()
+    }
+
+    pub fn get_waterfall(&self) -> &Waterfall {
+        &self.waterfall
+    }
+
+    /// Replace a root pointer by the waterfall of the file, see `FileDownloader::resolve_root_pointer`
+    pub async fn resolve_root_pointer(&mut self) -> std::io::Result<()> {
+        while self.waterfall.is_root_pointer() {
+            check_manifest_size(&self.waterfall)?;
+
+            let mut bytes = Vec::new();
+            self.reader().read_to_end(&mut bytes).await?;
+
+            let waterfall = parse_manifest(&self.waterfall, &bytes)?;
+
+            if !waterfall.password.is_empty() {
+                self.password = waterfall.password.clone();
+            }
+
+            self.waterfall = waterfall;
+        }
+
+        Ok(())
+    }
+
+    /// Seekable reader of the whole file
+    pub fn reader(&self) -> AsyncFileReader {
+        self.get_range(0, self.waterfall.size)
+    }
+
+    /// Reader starting at `start` and ending at `end`, clamped to the size of the file.
+    /// Only the chunks overlapping the range are downloaded.
+    pub fn get_range(&self, start: u64, end: u64) -> AsyncFileReader {
+        let end = min(end, self.waterfall.size);
+
+        AsyncFileReader {
+            downloader: self.clone(),
+            position: min(start, end),
+            end,
+            chunks: None,
+            container_end: 0,
+            skip: 0,
+            buffer: Vec::new(),
+            buffer_cursor: 0,
+        }
+    }
+
+    /// Download the file, `concurrency` containers at a time, and check its hash
+    pub async fn download_file(&self, file_path: String) -> std::io::Result<()> {
+        File::create(&file_path).await?.set_len(self.waterfall.size).await?;
+
+        let mut containers = self.waterfall.containers.clone();
+        containers.sort_by_key(|container| container.bytes_range[0]);
+
+        let queue = Mutex::new(VecDeque::from(containers));
+
+        let workers = (0..self.concurrency).map(|worker| {
+            let (queue, file_path) = (&queue, &file_path);
+
+            async move {
+                let mut file = OpenOptions::new().write(true).open(file_path).await?;
+
+                loop {
+                    let Some(container) = queue.lock().unwrap().pop_front() else {
+                        break;
+                    };
+
+                    if let Err(err) = self.download_container(&container, worker, &mut file).await {
+                        // the other workers stop after their current container
+                        queue.lock().unwrap().clear();
+                        return Err(err);
+                    }
+                }
+
+                Ok::<(), std::io::Error>(())
+            }
+        });
+
+        futures_util::future::try_join_all(workers).await?;
+
+        if let Some(expected_hash) = self.waterfall.file_hash {
+            let mut file = File::open(&file_path).await?;
+            let mut hasher = Sha256::new();
+            let mut buffer = vec![0u8; 1024 * 1024];
+
+            loop {
+                let read = file.read(&mut buffer).await?;
+
+                if read == 0 {
+                    break;
+                }
+
+                hasher.update(&buffer[..read]);
+            }
+
+            if expected_hash != <[u8; 32]>::from(hasher.finalize()) {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "File hash mismatch"));
+            }
+        }
+
+        self.events.report(TransferEvent::TransferDone);
+
+        Ok(())
+    }
+
+    /// Write the content of a container at its offset in `file`,
+    /// starting again from the failed chunk on errors
+    async fn download_container(&self, container: &Container, worker: usize, file: &mut File) -> std::io::Result<()> {
+        let index = container.index;
+        self.events.report(TransferEvent::ContainerStarted { worker, index });
+
+        let real_size = container.chunk_size - METADATA_SIZE as u64;
+        let last = content_chunk_count(container);
+
+        let mut chunk = 0;
+        let mut attempt = 0;
+
+        while chunk < last {
+            file.seek(SeekFrom::Start(container.bytes_range[0] + chunk * real_size)).await?;
+
+            let mut chunks = self.container_chunks(container, chunk, last, worker);
+
+            let result: std::io::Result<()> = async {
+                while let Some(content) = chunks.try_next().await? {
+                    file.write_all(&content).await?;
+                    chunk += 1;
+                    // retries are counted per failure, not for the whole container
+                    attempt = 0;
+                }
+
+                Ok(())
+            }.await;
+
+            match result {
+                Ok(()) => {}
+                Err(err) if attempt < DOWNLOAD_RETRIES => {
+                    attempt += 1;
+                    self.events.report(TransferEvent::Retry { worker, index, attempt, error: err.to_string() });
+
+                    tokio::time::sleep(Duration::from_secs(attempt as u64)).await;
+                }
+                Err(err) => {
+                    self.events.report(TransferEvent::ContainerFailed { worker, index, error: err.to_string() });
+                    return Err(err);
+                }
+            }
+        }
+
+        file.flush().await?;
+
+        self.events.report(TransferEvent::ContainerCommitted { worker, index, locator: container.storage_url.clone() });
+
+        Ok(())
+    }
+
+    /// Decrypted file bytes of the chunks `[first, last)` of a container
+    fn container_chunks(&self, container: &Container, first: u64, last: u64, worker: usize) -> BoxStream<'static, std::io::Result<Vec<u8>>> {
+        let state = ChunksState {
+            client: self.client.clone(),
+            container: container.clone(),
+            password: self.password.clone(),
+            keys: self.keys.clone(),
+            key: None,
+            file_size: self.waterfall.size,
+            events: self.events.clone(),
+            worker,
+            response: None,
+            pending: Vec::new(),
+            chunk: first,
+            last,
+        };
+
+        stream::try_unfold(state, |mut state| async move {
+            if state.chunk >= state.last {
+                return Ok(None);
+            }
+
+            let content = state.next_chunk().await?;
+            state.chunk += 1;
+
+            Ok(Some((content, state)))
+        }).boxed()
+    }
+}
+
+struct ChunksState {
+    client: Client,
+    container: Container,
+    password: String,
+    keys: KeyCache,
+    key: Option<[u8; 32]>,
+    file_size: u64,
+    events: StreamSignal<TransferEvent>,
+    worker: usize,
+
+    // positioned at the first byte of `pending`
+    response: Option<BoxStream<'static, reqwest::Result<Bytes>>>,
+    pending: Vec<u8>,
+    chunk: u64,
+    last: u64,
+}
+
+impl ChunksState {
+    /// Request the chunks from the current one to the last one
+    async fn open_response(&mut self) -> std::io::Result<()> {
+        let range_start = self.chunk * self.container.chunk_size;
+        let range_stop = self.last * self.container.chunk_size - 1;
+
+        let response = self.client.get(self.container.storage_url.clone())
+            .header("User-Agent", "Mozilla/5.0")
+            .header("Range", format!("bytes={}-{}", range_start, range_stop))
+            .send()
+            .await
+            .map_err(std::io::Error::other)?;
+
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(std::io::Error::other(format!("Invalid response status {}", response.status())));
+        }
+
+        self.response = Some(response.bytes_stream().boxed());
+
+        Ok(())
+    }
+
+    async fn next_chunk(&mut self) -> std::io::Result<Vec<u8>> {
+        let key = match self.key {
+            Some(key) => key,
+            None => *self.key.insert(self.keys.get(&self.password, &self.container).await?),
+        };
+
+        if self.response.is_none() {
+            self.open_response().await?;
+        }
+
+        let chunk_size = self.container.chunk_size as usize;
+
+        while self.pending.len() < chunk_size {
+            match self.response.as_mut().unwrap().next().await {
+                Some(bytes) => self.pending.extend_from_slice(&bytes.map_err(std::io::Error::other)?),
+                None => return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Container ended early")),
+            }
+        }
+
+        let mut chunk: Vec<u8> = self.pending.drain(..chunk_size).collect();
+
+        // AES and SHA-256 of a whole chunk, off the executor
+        let mut chunk = tokio::task::spawn_blocking(move || decrypt_chunk(&key, &mut chunk).map(|_| chunk))
+            .await
+            .map_err(std::io::Error::other)?
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+
+        let content_size = chunk_content_size(&self.container, self.file_size, self.chunk);
+        chunk.truncate(content_size);
+
+        self.events.report(TransferEvent::ChunkDecrypted {
+            worker: self.worker,
+            index: self.container.index,
+            chunk: self.chunk,
+            wire_bytes: chunk_size as u64,
+            plain_bytes: content_size as u64,
+        });
+
+        Ok(chunk)
+    }
+}
+
+// Reads the file from a position to the end of a range, one container
+// at a time. Positions of seeks are offsets in the whole file.
+// On errors, reading again opens a new request at the current position.
+pub struct AsyncFileReader {
+    downloader: AsyncDownloader,
+    position: u64,
+    end: u64,
+
+    chunks: Option<BoxStream<'static, std::io::Result<Vec<u8>>>>,
+    container_end: u64,
+    // bytes of the first chunk before the position
+    skip: usize,
+    buffer: Vec<u8>,
+    buffer_cursor: usize,
+}
+
+impl AsyncFileReader {
+    pub fn get_position(&self) -> u64 {
+        self.position
+    }
+
+    /// Open the chunks of the container holding the position, up to the end of the range
+    fn open_container(&mut self) -> std::io::Result<()> {
+        let container = self.downloader.waterfall.containers.iter()
+            .find(|container| self.position >= container.bytes_range[0] && self.position < container.bytes_range[1])
+            .cloned()
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("No container holds byte {}", self.position)))?;
+
+        let real_size = container.chunk_size - METADATA_SIZE as u64;
+        let offset = self.position - container.bytes_range[0];
+        let end = min(self.end, container.bytes_range[1]) - container.bytes_range[0];
+
+        let first = offset / real_size;
+        let last = min(content_chunk_count(&container), end.div_ceil(real_size));
+
+        self.chunks = Some(self.downloader.container_chunks(&container, first, last, 0));
+        self.container_end = min(self.end, container.bytes_range[1]);
+        self.skip = (offset - first * real_size) as usize;
+
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.chunks = None;
+        self.buffer.clear();
+        self.buffer_cursor = 0;
+    }
+}
+
+impl AsyncRead for AsyncFileReader {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let reader = self.get_mut();
+
+        loop {
+            if reader.buffer_cursor < reader.buffer.len() {
+                let remaining = min(reader.end - reader.position, (reader.buffer.len() - reader.buffer_cursor) as u64) as usize;
+                let size = min(buf.remaining(), remaining);
+
+                buf.put_slice(&reader.buffer[reader.buffer_cursor..(reader.buffer_cursor + size)]);
+                reader.buffer_cursor += size;
+                reader.position += size as u64;
+
+                return Poll::Ready(Ok(()));
+            }
+
+            if reader.position >= reader.end || buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+
+            if reader.chunks.is_none() {
+                if let Err(err) = reader.open_container() {
+                    return Poll::Ready(Err(err));
+                }
+            }
+
+            match reader.chunks.as_mut().unwrap().poll_next_unpin(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Some(Ok(content))) => {
+                    reader.buffer_cursor = min(reader.skip, content.len());
+                    reader.buffer = content;
+                    reader.skip = 0;
+                }
+                Poll::Ready(Some(Err(err))) => {
+                    reader.reset();
+                    return Poll::Ready(Err(err));
+                }
+                Poll::Ready(None) => {
+                    reader.chunks = None;
+
+                    if reader.position < reader.container_end {
+                        return Poll::Ready(Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Container ended early")));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl AsyncSeek for AsyncFileReader {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> std::io::Result<()> {
+        let reader = self.get_mut();
+
+        let target = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => reader.downloader.waterfall.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => reader.position.checked_add_signed(offset),
+        };
+
+        let target = target
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid seek to a negative position"))?;
+
+        if target != reader.position {
+            reader.reset();
+            reader.position = target;
+        }
+
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+        Poll::Ready(Ok(self.position))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::SeekFrom;
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+    use crate::async_downloader::AsyncDownloader;
+    use crate::common::{Container, Waterfall};
+    use crate::downloader::{serve_container, WaterfallDownloader};
+
+    #[test]
+    fn test_reader_positions() {
+        let waterfall = Waterfall {
+            filename: "file.bin".to_string(),
+            password: String::new(),
+            size: 100,
+            file_hash: None,
+            manifest_level: 0,
+            containers: vec![Container::test_fixture(1, [0, 100])],
+        };
+
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+
+        runtime.block_on(async {
+            let downloader = AsyncDownloader::from_waterfall(waterfall);
+
+            let mut reader = downloader.get_range(20, 500);
+            assert_eq!(reader.get_position(), 20);
+            assert_eq!(reader.seek(SeekFrom::End(-10)).await.unwrap(), 90);
+            assert_eq!(reader.seek(SeekFrom::Current(5)).await.unwrap(), 95);
+            assert!(reader.seek(SeekFrom::Current(-96)).await.is_err());
+
+            // nothing is requested past the end of the range
+            assert_eq!(reader.seek(SeekFrom::Start(100)).await.unwrap(), 100);
+            assert_eq!(reader.read(&mut [0u8; 10]).await.unwrap(), 0);
+
+            // the storage url cannot be reached
+            reader.seek(SeekFrom::Start(0)).await.unwrap();
+            assert!(reader.read(&mut [0u8; 10]).await.is_err());
+        });
+    }
+
+    #[test]
+    fn test_retries_per_failure() {
+        let content: Vec<u8> = (0..5000u32).map(|i| i as u8).collect();
+
+        // every response drops after one chunk, each later chunk fails once
+        let waterfall = Waterfall {
+            filename: "file.bin".to_string(),
+            password: String::new(),
+            size: content.len() as u64,
+            file_hash: None,
+            manifest_level: 0,
+            containers: vec![Container {
+                storage_url: serve_container(&content, 1024, 1),
+                chunk_size: 1024,
+                chunk_count: 6,
+                ..Container::test_fixture(1, [0, content.len() as u64])
+            }],
+        };
+
+        let path = std::env::temp_dir().join(format!("discord-us-async-retries-{}", rand::random::<u64>()));
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+
+        let result = runtime.block_on(AsyncDownloader::from_waterfall(waterfall).download_file(path.to_string_lossy().to_string()));
+        let downloaded = std::fs::read(&path);
+        let _ = std::fs::remove_file(&path);
+
+        result.unwrap();
+        assert_eq!(downloaded.unwrap(), content);
+    }
+}
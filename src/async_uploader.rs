@@ -0,0 +1,501 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io::SeekFrom;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use futures_core::Stream;
+use futures_util::stream::{self, StreamExt};
+use reqwest::{Body, Client, RequestBuilder};
+use sha2::{Digest, Sha256};
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, ReadBuf};
+use tokio::task::JoinHandle;
+use crate::chunk::{container_count, container_layout, derive_key_async, encrypt_chunk};
+use crate::common::{Container, Waterfall, METADATA_SIZE};
+use crate::credentials::{check_status, Credential, CredentialPool};
+use crate::http_client::{upload_headers, webhook_headers, ClientProfile};
+use crate::signal::{SignalStream, StreamSignal, TransferEvent};
+use crate::uploader::{attachment_payload, attachments_url, message_payload, messages_url, parse_attachment, parse_message, webhook_execute_url, ContainerUploadResult, FileUploadArguments, FileUploader, WaterfallExporter, WebhookForm};
+
+// Async uploader
+// Counterpart of `FileUploader` for tokio. Containers are encrypted while
+// they are sent, `concurrency` at a time, with the same layout as the
+// blocking uploader so both produce interchangeable waterfalls.
+
+pub struct AsyncUploader {
+    file_path: String,
+    file_size: u64,
+
+    container_size: u32,
+    chunk_size: u32,
+    concurrency: usize,
+
+    client: Client,
+    containers: Vec<Container>,
+    file_hash: Option<[u8; 32]>,
+    events: StreamSignal<TransferEvent>,
+}
+
+/// What an upload needs from `FileUploadArguments`, which cannot be sent between threads
+struct UploadTarget {
+    encryption_password: String,
+    credentials: CredentialPool,
+    profile: ClientProfile,
+    webhook_url: Option<String>,
+    retries: u32,
+    kdf_iterations: u32,
+}
+
+impl AsyncUploader {
+    /// Chunk and container sizes are checked like `FileUploader::check_chunk_size`
+    /// and `FileUploader::check_container_size`
+    pub async fn new(file_path: String, container_size: u32, chunk_size: u32) -> std::io::Result<AsyncUploader> {
+        FileUploader::check_chunk_size(chunk_size)?;
+        FileUploader::check_container_size(container_size as u64, chunk_size)?;
+
+        let file_size = tokio::fs::metadata(&file_path).await?.len();
+
+        Ok(AsyncUploader {
+            file_path,
+            file_size,
+            container_size,
+            chunk_size,
+            concurrency: 2,
+            client: Client::new(),
+            containers: Vec::new(),
+            file_hash: None,
+            events: StreamSignal::default(),
+        })
+    }
+
+    /// Number of containers uploaded at the same time, 2 by default
+    pub fn with_concurrency(&mut self, concurrency: usize) {
+        self.concurrency = concurrency.max(1);
+    }
+
+    /// Client shared by the transfers, see `HttpConfig::build_async`
+    pub fn with_client(&mut self, client: &Client) {
+        self.client = client.clone();
+    }
+
+    /// Events reported from now on, `worker` is the index of the concurrent upload.
+    /// A stream that falls behind loses the oldest events.
+    pub fn events(&mut self) -> SignalStream<TransferEvent> {
+        self.events.subscribe()
+    }
+
+    pub fn get_file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    /// Upload the containers not uploaded yet, returning the results of this pass
+    /// ordered by index. Failed containers are uploaded again by the next call.
+    /// Only the password, the credentials or webhook, the retries and the KDF iterations
+    /// of the arguments apply, progress is reported by `events()`.
+    pub fn upload(&mut self, arguments: &FileUploadArguments) -> impl Future<Output=Vec<ContainerUploadResult>> + Send + '_ {
+        let target = UploadTarget {
+            encryption_password: arguments.encryption_password.clone(),
+            credentials: arguments.credentials.clone(),
+            profile: arguments.profile.clone(),
+            webhook_url: arguments.webhook_url.clone(),
+            retries: arguments.retries,
+            kdf_iterations: arguments.kdf_iterations,
+        };
+
+        async move {
+            let count = container_count(self.file_size, self.container_size as u64, self.chunk_size as u64) as u32;
+
+            let queue: VecDeque<u32> = (1..=count)
+                .filter(|index| !self.containers.iter().any(|container| container.index == *index))
+                .collect();
+
+            let queue = Mutex::new(queue);
+            let results = Mutex::new(Vec::new());
+
+            let uploader = &*self;
+
+            let workers = (0..self.concurrency).map(|worker| {
+                let (target, queue, results) = (&target, &queue, &results);
+
+                async move {
+                    loop {
+                        let Some(index) = queue.lock().unwrap().pop_front() else {
+                            break;
+                        };
+
+                        let result = uploader.upload_with_retries(target, index, worker).await
+                            .map_err(|err| err.to_string());
+
+                        results.lock().unwrap().push(ContainerUploadResult { index, result });
+                    }
+                }
+            });
+
+            futures_util::future::join_all(workers).await;
+
+            let mut results = results.into_inner().unwrap();
+            results.sort_by_key(|result| result.index);
+
+            for result in results.iter() {
+                if let Ok(container) = &result.result {
+                    self.containers.push(container.clone());
+                }
+            }
+
+            self.containers.sort_by_key(|container| container.index);
+
+            // hashed again by the next call if it fails, exporting fails meanwhile
+            if self.containers.len() == count as usize && self.file_hash.is_none() {
+                self.file_hash = self.compute_file_hash().await.ok();
+            }
+
+            self.events.report(TransferEvent::TransferDone);
+
+            results
+        }
+    }
+
+    async fn upload_with_retries(&self, target: &UploadTarget, index: u32, worker: usize) -> std::io::Result<Container> {
+        self.events.report(TransferEvent::ContainerStarted { worker, index });
+
+        // would be rejected by Discord on every attempt
+        if target.webhook_url.is_some() {
+            if let Err(err) = FileUploader::check_webhook_container_size(self.container_size as u64) {
+                self.events.report(TransferEvent::ContainerFailed { worker, index, error: err.to_string() });
+                return Err(err);
+            }
+        }
+
+        let mut attempt = 0;
+
+        loop {
+            match self.upload_container(target, index, worker).await {
+                Ok(container) => {
+                    self.events.report(TransferEvent::ContainerCommitted { worker, index, locator: container.storage_url.clone() });
+                    return Ok(container);
+                }
+                Err(err) if attempt < target.retries => {
+                    attempt += 1;
+                    self.events.report(TransferEvent::Retry { worker, index, attempt, error: err.to_string() });
+
+                    tokio::time::sleep(Duration::from_secs(attempt as u64)).await;
+                }
+                Err(err) => {
+                    self.events.report(TransferEvent::ContainerFailed { worker, index, error: err.to_string() });
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    async fn upload_container(&self, target: &UploadTarget, index: u32, worker: usize) -> std::io::Result<Container> {
+        let filename = "data.enc".to_string();
+
+        let salt: [u8; 16] = rand::random();
+        let key = derive_key_async(target.encryption_password.clone(), salt, target.kdf_iterations).await?;
+
+        let layout = container_layout(self.file_size, self.container_size, self.chunk_size, index);
+
+        let mut file = File::open(&self.file_path).await?;
+        file.seek(SeekFrom::Start(layout.cursor)).await?;
+
+        let body = EncryptedBody::new(file, key, layout.size, self.chunk_size as usize, self.events.clone(), worker, index);
+
+        let (message_id, storage_url, credential) = match &target.webhook_url {
+            // the container is sent with the message itself
+            Some(webhook_url) => {
+                self.events.report(TransferEvent::SlotReserved { worker, index, size: layout.size });
+
+                let form = WebhookForm::new(&filename);
+                let form_size = form.size(layout.size);
+
+                let body = stream::iter([Ok(form.head)])
+                    .chain(body)
+                    .chain(stream::iter([Ok(form.tail)]));
+
+                let response = discord_json(self.client.post(webhook_execute_url(webhook_url))
+                    .headers(webhook_headers(&form.content_type, form_size, &target.profile))
+                    .body(Body::wrap_stream(body))).await?;
+
+                let (message_id, storage_url) = parse_message(&response)?;
+
+                (message_id, storage_url, None)
+            }
+            None => {
+                let lease = target.credentials.acquire_async().await
+                    .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::PermissionDenied, "Every credential is disabled"))?;
+
+                let result = self.post_container(target, &lease.credential, filename, body, worker, index).await;
+
+                match &result {
+                    Ok(_) => lease.uploaded(),
+                    Err(err) => lease.failed(err),
+                }
+
+                let (message_id, storage_url) = result?;
+
+                (message_id, storage_url, Some(lease.credential.clone()))
+            }
+        };
+
+        Ok(Container {
+            index,
+            storage_url,
+            message_id: Some(message_id),
+            channel_id: credential.as_ref().map(|credential| credential.channel_id),
+            credential: credential.map(|credential| credential.name).filter(|name| !name.is_empty()),
+            chunk_size: self.chunk_size as u64,
+            chunk_count: layout.size / self.chunk_size as u64,
+            salt,
+            kdf_iterations: target.kdf_iterations,
+            bytes_range: [layout.cursor, layout.end],
+        })
+    }
+
+    /// Reserve an attachment, send the container to it and post its message
+    async fn post_container(&self, target: &UploadTarget, credential: &Credential, filename: String, body: EncryptedBody, worker: usize, index: u32) -> std::io::Result<(u64, String)> {
+        let size = body.remaining_size;
+
+        let response = discord_json(self.client.post(attachments_url(credential.channel_id))
+            .headers(target.profile.get_headers(&credential.token))
+            .json(&attachment_payload(filename.clone(), size))).await?;
+
+        let (upload_url, upload_filename) = parse_attachment(&response)?;
+
+        self.events.report(TransferEvent::SlotReserved { worker, index, size });
+
+        self.client.put(upload_url)
+            .headers(upload_headers(size, &target.profile))
+            .body(Body::wrap_stream(body))
+            .send().await
+            .and_then(|response| response.error_for_status())
+            .map_err(std::io::Error::other)?;
+
+        let response = discord_json(self.client.post(messages_url(credential.channel_id))
+            .headers(target.profile.get_headers(&credential.token))
+            .json(&message_payload(credential.channel_id, filename, upload_filename))).await?;
+
+        parse_message(&response)
+    }
+
+    async fn compute_file_hash(&self) -> std::io::Result<[u8; 32]> {
+        let mut file = File::open(&self.file_path).await?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0u8; 1024 * 1024];
+
+        loop {
+            let read = file.read(&mut buffer).await?;
+
+            if read == 0 {
+                break;
+            }
+
+            hasher.update(&buffer[..read]);
+        }
+
+        Ok(hasher.finalize().into())
+    }
+}
+
+/// Send a Discord API request, see `check_status` for the errors
+async fn discord_json(request: RequestBuilder) -> std::io::Result<serde_json::Value> {
+    let response = request.send().await.map_err(std::io::Error::other)?;
+
+    check_status(response.status(), response.headers())?;
+
+    response.json::<serde_json::Value>().await.map_err(std::io::Error::other)
+}
+
+impl WaterfallExporter for AsyncUploader {
+    fn export_waterfall(&self) -> std::io::Result<Waterfall> {
+        self.export_waterfall_with_password(String::new())
+    }
+
+    /// Fails until every container is uploaded
+    fn export_waterfall_with_password(&self, password: String) -> std::io::Result<Waterfall> {
+        let count = container_count(self.file_size, self.container_size as u64, self.chunk_size as u64);
+
+        if self.containers.len() < count {
+            return Err(std::io::Error::other(format!("Upload is not finished ({} of {} containers uploaded)", self.containers.len(), count)));
+        }
+
+        let Some(file_hash) = self.file_hash else {
+            return Err(std::io::Error::other("The file could not be hashed, upload again to retry"));
+        };
+
+        let waterfall = Waterfall {
+            containers: self.containers.clone(),
+            size: self.file_size,
+            filename: self.file_path.clone(),
+            password,
+            file_hash: Some(file_hash),
+            manifest_level: 0,
+        };
+
+        waterfall.validate()?;
+
+        Ok(waterfall)
+    }
+}
+
+// Encrypted container
+// Body of the upload of a container, reads and encrypts
+// one chunk of the file each time the request asks for more.
+// Chunks are encrypted on the blocking threads of tokio.
+struct EncryptedBody {
+    file: File,
+    key: [u8; 32],
+    remaining_size: u64,
+
+    buffer: Vec<u8>,
+    // bytes of the content of the chunk read from the file
+    filled: usize,
+    end_of_file: bool,
+    // the chunk being encrypted and the file bytes it holds
+    encrypting: Option<(JoinHandle<Vec<u8>>, u64)>,
+
+    events: StreamSignal<TransferEvent>,
+    worker: usize,
+    index: u32,
+    chunk: u64,
+}
+
+impl EncryptedBody {
+    fn new(file: File, key: [u8; 32], size: u64, chunk_size: usize, events: StreamSignal<TransferEvent>, worker: usize, index: u32) -> EncryptedBody {
+        EncryptedBody {
+            file,
+            key,
+            remaining_size: size,
+            buffer: vec![0; chunk_size],
+            filled: 0,
+            end_of_file: false,
+            encrypting: None,
+            events,
+            worker,
+            index,
+            chunk: 0,
+        }
+    }
+}
+
+impl Stream for EncryptedBody {
+    type Item = std::io::Result<Vec<u8>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let body = self.get_mut();
+
+        let chunk_size = body.buffer.len();
+
+        if let Some((handle, plain_bytes)) = body.encrypting.as_mut() {
+            let plain_bytes = *plain_bytes;
+
+            let chunk = match Pin::new(handle).poll(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(result) => result.map_err(std::io::Error::other),
+            };
+
+            body.encrypting = None;
+
+            if chunk.is_ok() {
+                body.events.report(TransferEvent::ChunkEncrypted { worker: body.worker, index: body.index, chunk: body.chunk, wire_bytes: chunk_size as u64, plain_bytes });
+                body.chunk += 1;
+            }
+
+            return Poll::Ready(Some(chunk));
+        }
+
+        if body.remaining_size == 0 {
+            return Poll::Ready(None);
+        }
+
+        let content_size = chunk_size - METADATA_SIZE;
+
+        // the tail past the end of the file is zeroed, like the blocking uploader does
+        while body.filled < content_size && !body.end_of_file {
+            let mut read_buf = ReadBuf::new(&mut body.buffer[body.filled..content_size]);
+
+            match Pin::new(&mut body.file).poll_read(cx, &mut read_buf) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(err)) => return Poll::Ready(Some(Err(err))),
+                Poll::Ready(Ok(())) => match read_buf.filled().len() {
+                    0 => body.end_of_file = true,
+                    read => body.filled += read,
+                },
+            }
+        }
+
+        body.buffer[body.filled..content_size].fill(0);
+
+        let iv: [u8; 16] = rand::random();
+        let key = body.key;
+        let mut chunk = std::mem::replace(&mut body.buffer, vec![0; chunk_size]);
+
+        let handle = tokio::task::spawn_blocking(move || {
+            encrypt_chunk(&key, &iv, &mut chunk);
+            chunk
+        });
+
+        body.encrypting = Some((handle, body.filled as u64));
+        body.filled = 0;
+        body.remaining_size -= chunk_size as u64;
+
+        // polls the encryption
+        Pin::new(body).poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+    use crate::async_uploader::{AsyncUploader, EncryptedBody};
+    use crate::chunk::{container_layout, decrypt_chunk};
+    use crate::common::METADATA_SIZE;
+    use crate::signal::{StreamSignal, TransferEvent};
+    use crate::uploader::WaterfallExporter;
+
+    #[test]
+    fn test_encrypted_body() {
+        let chunk_size = 16 * 1024;
+        let real_size = chunk_size - METADATA_SIZE;
+
+        let data: Vec<u8> = (0..(2 * real_size + 100)).map(|i| (i % 251) as u8).collect();
+        let path = std::env::temp_dir().join(format!("discord-us-async-{}", rand::random::<u64>()));
+        std::fs::write(&path, &data).unwrap();
+
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+
+        runtime.block_on(async {
+            let uploader = AsyncUploader::new(path.to_string_lossy().to_string(), 4 * chunk_size as u32, chunk_size as u32).await.unwrap();
+            assert!(uploader.export_waterfall().is_err());
+
+            let layout = container_layout(data.len() as u64, 4 * chunk_size as u32, chunk_size as u32, 1);
+            assert_eq!(layout.size, 3 * chunk_size as u64);
+
+            let events = StreamSignal::default();
+            let mut event_stream = events.subscribe();
+
+            let file = tokio::fs::File::open(&path).await.unwrap();
+            let body = EncryptedBody::new(file, [5u8; 32], layout.size, chunk_size, events, 0, 1);
+
+            let chunks: Vec<Vec<u8>> = body.map(|chunk| chunk.unwrap()).collect().await;
+            assert_eq!(chunks.len(), 3);
+
+            let mut content = Vec::new();
+
+            for mut chunk in chunks {
+                decrypt_chunk(&[5u8; 32], &mut chunk).unwrap();
+                content.extend_from_slice(&chunk[..real_size]);
+            }
+
+            assert_eq!(&content[..data.len()], data.as_slice());
+            assert!(content[data.len()..].iter().all(|byte| *byte == 0));
+
+            let event = event_stream.next().await;
+            assert_eq!(event, Some(TransferEvent::ChunkEncrypted { worker: 0, index: 1, chunk: 0, wire_bytes: chunk_size as u64, plain_bytes: real_size as u64 }));
+        });
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
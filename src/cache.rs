@@ -0,0 +1,280 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use crate::common::Container;
+
+// Chunk cache
+// Encrypted chunks as downloaded, one file per chunk named after the
+// container salt and the chunk index. Chunks are decrypted and their hash
+// checked on every hit, like chunks coming from the network.
+// The modification time of the files keeps the LRU order between processes.
+
+const CHUNK_EXTENSION: &str = "chunk";
+
+struct CacheEntry {
+    size: u64,
+    // position in the LRU order
+    tick: u64,
+}
+
+// Only the bookkeeping is done under the lock, files are read, written
+// and deleted after it is released
+#[derive(Default)]
+struct CacheState {
+    size: u64,
+    entries: HashMap<String, CacheEntry>,
+    // least recently used first
+    order: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+#[derive(Clone)]
+pub struct ChunkCache {
+    directory: PathBuf,
+    max_size: u64,
+    state: Arc<Mutex<CacheState>>,
+}
+
+impl ChunkCache {
+    /// Open the cache, creating the directory if needed,
+    /// and evict chunks until it holds at most `max_size` bytes.
+    /// Temporary files left by an interrupted insert are removed.
+    pub fn open<P: AsRef<Path>>(directory: P, max_size: u64) -> std::io::Result<ChunkCache> {
+        let directory = directory.as_ref().to_path_buf();
+        std::fs::create_dir_all(&directory)?;
+
+        let mut files = Vec::new();
+
+        for entry in std::fs::read_dir(&directory)? {
+            let entry = entry?;
+            let path = entry.path();
+
+            if path.extension().and_then(|ext| ext.to_str()) != Some(CHUNK_EXTENSION) {
+                // <chunk>.<random>, written by insert before its rename
+                if path.file_stem().and_then(|stem| Path::new(stem).extension()).and_then(|ext| ext.to_str()) == Some(CHUNK_EXTENSION) {
+                    let _ = std::fs::remove_file(&path);
+                }
+
+                continue;
+            }
+
+            let metadata = entry.metadata()?;
+            let name = entry.file_name().to_string_lossy().to_string();
+
+            files.push((metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH), name, metadata.len()));
+        }
+
+        files.sort();
+
+        let mut state = CacheState::default();
+
+        for (_, name, size) in files {
+            state.insert(name, size);
+        }
+
+        let cache = ChunkCache { directory, max_size, state: Arc::new(Mutex::new(state)) };
+
+        let evicted = cache.state.lock().unwrap().evict(max_size);
+        cache.delete_files(evicted);
+
+        Ok(cache)
+    }
+
+    pub fn contains(&self, container: &Container, chunk: u64) -> bool {
+        self.state.lock().unwrap().entries.contains_key(&Self::chunk_name(container, chunk))
+    }
+
+    /// The encrypted chunk, if cached with the expected size
+    pub fn get(&self, container: &Container, chunk: u64) -> Option<Vec<u8>> {
+        let name = Self::chunk_name(container, chunk);
+
+        if !self.state.lock().unwrap().entries.contains_key(&name) {
+            return None;
+        }
+
+        let path = self.directory.join(&name);
+
+        match std::fs::read(&path) {
+            Ok(bytes) if bytes.len() as u64 == container.chunk_size => {
+                self.state.lock().unwrap().touch(&name);
+
+                // best effort, the order is only lost for other processes
+                let _ = File::options().write(true).open(&path)
+                    .and_then(|file| file.set_modified(SystemTime::now()));
+
+                Some(bytes)
+            }
+            _ => {
+                self.state.lock().unwrap().remove(&name);
+                self.delete_files(vec![name]);
+                None
+            }
+        }
+    }
+
+    pub fn insert(&self, container: &Container, chunk: u64, bytes: &[u8]) -> std::io::Result<()> {
+        if bytes.len() as u64 > self.max_size {
+            return Ok(());
+        }
+
+        let name = Self::chunk_name(container, chunk);
+
+        // write then rename, readers never see a partial chunk
+        let path = self.directory.join(&name);
+        let temporary_path = self.directory.join(format!("{}.{}", name, rand::random::<u32>()));
+
+        if let Err(err) = std::fs::write(&temporary_path, bytes).and_then(|_| std::fs::rename(&temporary_path, &path)) {
+            let _ = std::fs::remove_file(&temporary_path);
+            return Err(err);
+        }
+
+        let evicted = {
+            let mut state = self.state.lock().unwrap();
+
+            state.insert(name, bytes.len() as u64);
+            state.evict(self.max_size)
+        };
+
+        self.delete_files(evicted);
+
+        Ok(())
+    }
+
+    /// Drop a chunk, for example when it does not pass the integrity check
+    pub fn remove(&self, container: &Container, chunk: u64) {
+        let name = Self::chunk_name(container, chunk);
+
+        self.state.lock().unwrap().remove(&name);
+        self.delete_files(vec![name]);
+    }
+
+    /// Number of bytes of the cached chunks
+    pub fn get_size(&self) -> u64 {
+        self.state.lock().unwrap().size
+    }
+
+    pub fn clear(&self) {
+        let evicted = self.state.lock().unwrap().evict(0);
+
+        self.delete_files(evicted);
+    }
+
+    fn delete_files(&self, names: Vec<String>) {
+        for name in names {
+            let _ = std::fs::remove_file(self.directory.join(name));
+        }
+    }
+
+    fn chunk_name(container: &Container, chunk: u64) -> String {
+        let salt: String = container.salt.iter().map(|b| format!("{:02x}", b)).collect();
+
+        format!("{}-{}.{}", salt, chunk, CHUNK_EXTENSION)
+    }
+}
+
+impl CacheState {
+    /// Add or replace a chunk as the most recently used
+    fn insert(&mut self, name: String, size: u64) {
+        self.remove(&name);
+
+        self.tick += 1;
+        self.size += size;
+        self.order.insert(self.tick, name.clone());
+        self.entries.insert(name, CacheEntry { size, tick: self.tick });
+    }
+
+    fn touch(&mut self, name: &String) {
+        if let Some(entry) = self.entries.get_mut(name) {
+            self.order.remove(&entry.tick);
+
+            self.tick += 1;
+            entry.tick = self.tick;
+            self.order.insert(self.tick, name.clone());
+        }
+    }
+
+    fn remove(&mut self, name: &String) {
+        if let Some(entry) = self.entries.remove(name) {
+            self.size -= entry.size;
+            self.order.remove(&entry.tick);
+        }
+    }
+
+    /// Forget the least recently used chunks above `max_size`,
+    /// returning the files to delete
+    fn evict(&mut self, max_size: u64) -> Vec<String> {
+        let mut evicted = Vec::new();
+
+        while self.size > max_size {
+            let Some((_, name)) = self.order.pop_first() else {
+                break;
+            };
+
+            if let Some(entry) = self.entries.remove(&name) {
+                self.size -= entry.size;
+            }
+
+            evicted.push(name);
+        }
+
+        evicted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cache::ChunkCache;
+    use crate::common::Container;
+
+    fn container(salt: u8) -> Container {
+        Container { chunk_size: 4, salt: [salt; 16], ..Container::test_fixture(1, [0, 0]) }
+    }
+
+    #[test]
+    fn test_chunk_cache() {
+        let directory = std::env::temp_dir().join(format!("discord-us-cache-{}", rand::random::<u64>()));
+        let cache = ChunkCache::open(&directory, 12).unwrap();
+
+        let (a, b) = (container(1), container(2));
+
+        cache.insert(&a, 0, &[1, 2, 3, 4]).unwrap();
+        cache.insert(&a, 1, &[5, 6, 7, 8]).unwrap();
+        cache.insert(&b, 0, &[9, 9, 9, 9]).unwrap();
+        assert_eq!(cache.get_size(), 12);
+
+        assert_eq!(cache.get(&a, 0), Some(vec![1, 2, 3, 4]));
+        assert_eq!(cache.get(&b, 1), None);
+        assert!(cache.contains(&b, 0));
+
+        // (a, 1) is the least recently used
+        cache.insert(&b, 1, &[0, 0, 0, 0]).unwrap();
+        assert_eq!(cache.get_size(), 12);
+        assert_eq!(cache.get(&a, 1), None);
+        assert_eq!(cache.get(&a, 0), Some(vec![1, 2, 3, 4]));
+
+        // chunks of another size are not trusted
+        cache.insert(&b, 2, &[1, 2]).unwrap();
+        assert_eq!(cache.get(&b, 2), None);
+
+        cache.remove(&a, 0);
+        assert_eq!(cache.get(&a, 0), None);
+
+        // another process sees the cached chunks, within its own limit
+        let reopened = ChunkCache::open(&directory, 4).unwrap();
+        assert_eq!(reopened.get_size(), 4);
+        assert_eq!(reopened.get(&b, 1), Some(vec![0, 0, 0, 0]));
+
+        reopened.clear();
+        assert_eq!(reopened.get_size(), 0);
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 0);
+
+        // a chunk left before its rename is not kept
+        std::fs::write(directory.join(format!("{}.{}", ChunkCache::chunk_name(&a, 0), 7)), [1, 2, 3, 4]).unwrap();
+        assert_eq!(ChunkCache::open(&directory, 12).unwrap().get_size(), 0);
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 0);
+
+        std::fs::remove_dir(&directory).unwrap();
+    }
+}
@@ -0,0 +1,278 @@
+use std::fs::OpenOptions;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use sha2::{Digest, Sha256};
+use crate::common::{Waterfall, WaterfallFormat};
+
+// Catalog
+// SQLite database of the completed uploads, keeping a copy of each
+// waterfall so a lost `.waterfall` file can be exported again.
+// The waterfalls keep their password: the catalog is a keyring,
+// readable and writable by its owner only.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS uploads (
+        id INTEGER PRIMARY KEY,
+        filename TEXT NOT NULL,
+        source_path TEXT,
+        waterfall_path TEXT,
+        size INTEGER NOT NULL,
+        file_hash BLOB,
+        waterfall_hash BLOB NOT NULL,
+        channel_id INTEGER,
+        uploaded_at INTEGER NOT NULL,
+        waterfall BLOB NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS tags (
+        upload_id INTEGER NOT NULL,
+        tag TEXT NOT NULL,
+        PRIMARY KEY (upload_id, tag)
+    );
+";
+
+const ENTRY_COLUMNS: &str = "id, filename, source_path, waterfall_path, size, file_hash, waterfall_hash, channel_id, uploaded_at";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CatalogEntry {
+    pub id: i64,
+    pub filename: String,
+    pub source_path: Option<String>,
+    pub waterfall_path: Option<String>,
+    pub size: u64,
+    pub file_hash: Option<[u8; 32]>,
+    /// SHA-256 of the binary encoding of the waterfall
+    pub waterfall_hash: [u8; 32],
+    pub channel_id: Option<u64>,
+    /// Unix timestamp in seconds
+    pub uploaded_at: u64,
+    pub tags: Vec<String>,
+}
+
+pub struct Catalog {
+    connection: Connection,
+}
+
+impl Catalog {
+    /// Open the catalog, creating the file and its parent directories if needed.
+    /// On Unix the file is restricted to its owner, SQLite gives its journal
+    /// the same permissions.
+    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Catalog> {
+        if let Some(parent) = path.as_ref().parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(false);
+
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        let file = options.open(&path)?;
+
+        // catalogs created before their permissions were restricted
+        #[cfg(unix)]
+        file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+
+        drop(file);
+
+        Self::init(Connection::open(path).map_err(std::io::Error::other)?)
+    }
+
+    pub fn open_in_memory() -> std::io::Result<Catalog> {
+        Self::init(Connection::open_in_memory().map_err(std::io::Error::other)?)
+    }
+
+    fn init(connection: Connection) -> std::io::Result<Catalog> {
+        connection.execute_batch(SCHEMA).map_err(std::io::Error::other)?;
+
+        Ok(Catalog { connection })
+    }
+
+    /// Record a completed upload, returning its id
+    pub fn register(&mut self, waterfall: &Waterfall, source_path: Option<String>, waterfall_path: Option<String>, channel_id: Option<u64>, tags: &[String]) -> std::io::Result<i64> {
+        let bytes = waterfall.to_bytes(WaterfallFormat::Binary)?;
+        let waterfall_hash: [u8; 32] = Sha256::digest(&bytes).into();
+
+        let uploaded_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+
+        let transaction = self.connection.transaction().map_err(std::io::Error::other)?;
+
+        transaction.execute(
+            "INSERT INTO uploads (filename, source_path, waterfall_path, size, file_hash, waterfall_hash, channel_id, uploaded_at, waterfall)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                waterfall.filename,
+                source_path,
+                waterfall_path,
+                waterfall.size as i64,
+                waterfall.file_hash.map(|hash| hash.to_vec()),
+                waterfall_hash.to_vec(),
+                channel_id.map(|id| id as i64),
+                uploaded_at as i64,
+                bytes,
+            ],
+        ).map_err(std::io::Error::other)?;
+
+        let id = transaction.last_insert_rowid();
+
+        for tag in tags {
+            transaction.execute("INSERT OR IGNORE INTO tags (upload_id, tag) VALUES (?1, ?2)", params![id, tag])
+                .map_err(std::io::Error::other)?;
+        }
+
+        transaction.commit().map_err(std::io::Error::other)?;
+
+        Ok(id)
+    }
+
+    pub fn list(&self) -> std::io::Result<Vec<CatalogEntry>> {
+        self.query(&format!("SELECT {} FROM uploads ORDER BY id", ENTRY_COLUMNS), params![])
+    }
+
+    /// Uploads whose filename or paths contain `text`, case insensitive,
+    /// and carrying every tag of `tags`
+    pub fn search(&self, text: Option<&str>, tags: &[String]) -> std::io::Result<Vec<CatalogEntry>> {
+        let pattern = format!("%{}%", escape_like(text.unwrap_or("")));
+
+        let entries = self.query(
+            &format!(
+                "SELECT {} FROM uploads
+                 WHERE filename LIKE ?1 ESCAPE '\\' OR source_path LIKE ?1 ESCAPE '\\' OR waterfall_path LIKE ?1 ESCAPE '\\'
+                 ORDER BY id",
+                ENTRY_COLUMNS,
+            ),
+            params![pattern],
+        )?;
+
+        Ok(entries.into_iter()
+            .filter(|entry| tags.iter().all(|tag| entry.tags.contains(tag)))
+            .collect())
+    }
+
+    pub fn get(&self, id: i64) -> std::io::Result<Option<CatalogEntry>> {
+        Ok(self.query(&format!("SELECT {} FROM uploads WHERE id = ?1", ENTRY_COLUMNS), params![id])?.pop())
+    }
+
+    pub fn get_waterfall(&self, id: i64) -> std::io::Result<Option<Waterfall>> {
+        let bytes: Option<Vec<u8>> = self.connection
+            .query_row("SELECT waterfall FROM uploads WHERE id = ?1", params![id], |row| row.get(0))
+            .optional()
+            .map_err(std::io::Error::other)?;
+
+        bytes.map(|bytes| Waterfall::from_bytes(&bytes)).transpose()
+    }
+
+    /// Remove an upload from the catalog, returns false if it is unknown.
+    /// The containers themselves stay on Discord.
+    pub fn forget(&mut self, id: i64) -> std::io::Result<bool> {
+        let transaction = self.connection.transaction().map_err(std::io::Error::other)?;
+
+        transaction.execute("DELETE FROM tags WHERE upload_id = ?1", params![id]).map_err(std::io::Error::other)?;
+        let deleted = transaction.execute("DELETE FROM uploads WHERE id = ?1", params![id]).map_err(std::io::Error::other)?;
+
+        transaction.commit().map_err(std::io::Error::other)?;
+
+        Ok(deleted > 0)
+    }
+
+    fn query<P: rusqlite::Params>(&self, sql: &str, params: P) -> std::io::Result<Vec<CatalogEntry>> {
+        let mut statement = self.connection.prepare(sql).map_err(std::io::Error::other)?;
+
+        let mut entries = statement.query_map(params, read_entry)
+            .and_then(|rows| rows.collect::<rusqlite::Result<Vec<CatalogEntry>>>())
+            .map_err(std::io::Error::other)?;
+
+        let mut tags_statement = self.connection.prepare("SELECT tag FROM tags WHERE upload_id = ?1 ORDER BY tag")
+            .map_err(std::io::Error::other)?;
+
+        for entry in entries.iter_mut() {
+            entry.tags = tags_statement.query_map(params![entry.id], |row| row.get(0))
+                .and_then(|rows| rows.collect())
+                .map_err(std::io::Error::other)?;
+        }
+
+        Ok(entries)
+    }
+}
+
+fn read_entry(row: &Row) -> rusqlite::Result<CatalogEntry> {
+    Ok(CatalogEntry {
+        id: row.get(0)?,
+        filename: row.get(1)?,
+        source_path: row.get(2)?,
+        waterfall_path: row.get(3)?,
+        size: row.get::<_, i64>(4)? as u64,
+        file_hash: row.get::<_, Option<Vec<u8>>>(5)?.and_then(|hash| hash.try_into().ok()),
+        waterfall_hash: row.get::<_, Vec<u8>>(6)?.try_into().unwrap_or([0u8; 32]),
+        channel_id: row.get::<_, Option<i64>>(7)?.map(|id| id as u64),
+        uploaded_at: row.get::<_, i64>(8)? as u64,
+        tags: Vec::new(),
+    })
+}
+
+fn escape_like(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::catalog::Catalog;
+    use crate::common::{Container, Waterfall};
+
+    fn waterfall(filename: &str) -> Waterfall {
+        Waterfall {
+            filename: filename.to_string(),
+            password: "password".to_string(),
+            size: 10,
+            file_hash: Some([1u8; 32]),
+            manifest_level: 0,
+            containers: vec![Container {
+                storage_url: "https://cdn.discordapp.com/attachments/1/2/data.enc".to_string(),
+                salt: [2u8; 16],
+                ..Container::test_fixture(1, [0, 10])
+            }],
+        }
+    }
+
+    #[test]
+    fn test_catalog() {
+        let mut catalog = Catalog::open_in_memory().unwrap();
+
+        let tags = vec!["photos".to_string(), "2023".to_string()];
+        let photos = catalog.register(&waterfall("holidays_2023.tar"), Some("/home/me/holidays_2023.tar".to_string()), Some("/home/me/holidays.waterfall".to_string()), Some(42), &tags).unwrap();
+        let backup = catalog.register(&waterfall("backup.zip"), None, None, None, &["backup".to_string()]).unwrap();
+
+        let entries = catalog.list().unwrap();
+        assert_eq!(entries.iter().map(|e| e.id).collect::<Vec<_>>(), vec![photos, backup]);
+        assert_eq!(entries[0].tags, vec!["2023".to_string(), "photos".to_string()]);
+        assert_eq!(entries[0].channel_id, Some(42));
+        assert_eq!(entries[0].file_hash, Some([1u8; 32]));
+        assert_eq!(entries[1].source_path, None);
+
+        let ids = |entries: Vec<crate::catalog::CatalogEntry>| entries.iter().map(|e| e.id).collect::<Vec<_>>();
+        assert_eq!(ids(catalog.search(Some("HOLIDAYS"), &[]).unwrap()), vec![photos]);
+        assert_eq!(ids(catalog.search(Some("/home/me"), &[]).unwrap()), vec![photos]);
+        assert_eq!(ids(catalog.search(None, &["backup".to_string()]).unwrap()), vec![backup]);
+        assert_eq!(ids(catalog.search(Some("_"), &[]).unwrap()), vec![photos]);
+        assert!(catalog.search(Some("%"), &[]).unwrap().is_empty());
+
+        let restored = catalog.get_waterfall(photos).unwrap().unwrap();
+        assert_eq!(serde_json::to_value(&restored).unwrap(), serde_json::to_value(waterfall("holidays_2023.tar")).unwrap());
+
+        assert!(catalog.forget(photos).unwrap());
+        assert!(!catalog.forget(photos).unwrap());
+        assert_eq!(catalog.get(photos).unwrap(), None);
+        assert!(catalog.get_waterfall(photos).unwrap().is_none());
+        assert_eq!(ids(catalog.list().unwrap()), vec![backup]);
+
+        // the waterfalls hold their passwords, only the owner can read them
+        let path = std::env::temp_dir().join(format!("discord-us-catalog-{}.db", rand::random::<u64>()));
+        Catalog::open(&path).unwrap().register(&waterfall("backup.zip"), None, None, None, &[]).unwrap();
+
+        #[cfg(unix)]
+        assert_eq!(std::os::unix::fs::PermissionsExt::mode(&std::fs::metadata(&path).unwrap().permissions()) & 0o777, 0o600);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,176 @@
+use std::cmp::min;
+use aes::Aes256;
+use block_modes::block_padding::Pkcs7;
+use block_modes::{BlockMode, Cbc};
+use hmac::Hmac;
+use pbkdf2::pbkdf2;
+use sha2::{Digest, Sha256};
+use crate::common::{Container, METADATA_SIZE};
+
+// Chunks and containers
+// Layout shared by the blocking and the async transfers.
+// | AES-256-CBC of the content, PKCS7 padded | IV (16) | SHA-256 of the content (32) |
+// The content is `chunk_size - METADATA_SIZE` bytes, zero filled past the end of
+// the file, so the padded ciphertext always ends at `chunk_size - 48`.
+type Aes256Cbc = Cbc<Aes256, Pkcs7>;
+
+/// Key of a container, derived from the password and the salt of the container
+pub(crate) fn derive_key(password: &str, salt: &[u8; 16], iterations: u32) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    pbkdf2::<Hmac<Sha256>>(password.as_bytes(), salt, iterations, &mut key);
+    key
+}
+
+/// `derive_key` on the blocking threads of tokio, PBKDF2 would hold up the executor
+#[cfg(feature = "async")]
+pub(crate) async fn derive_key_async(password: String, salt: [u8; 16], iterations: u32) -> std::io::Result<[u8; 32]> {
+    tokio::task::spawn_blocking(move || derive_key(&password, &salt, iterations))
+        .await
+        .map_err(std::io::Error::other)
+}
+
+/// Encrypt the content held in `chunk[..chunk.len() - METADATA_SIZE]` in place,
+/// then write the IV and the hash of the content at the end of the chunk
+pub(crate) fn encrypt_chunk(key: &[u8; 32], iv: &[u8; 16], chunk: &mut [u8]) {
+    let chunk_size = chunk.len();
+    let content_size = chunk_size - METADATA_SIZE;
+
+    let hash = Sha256::digest(&chunk[..content_size]);
+
+    let cipher = Aes256Cbc::new_from_slices(key, iv).unwrap();
+
+    cipher.encrypt(&mut chunk[..content_size + 16], content_size)
+        .expect("encryption failure!");
+
+    chunk[chunk_size - 48..chunk_size - 32].clone_from_slice(iv);
+    chunk[chunk_size - 32..].clone_from_slice(&hash);
+}
+
+/// Decrypt a chunk in place and check its hash,
+/// the content is then `chunk[..chunk.len() - METADATA_SIZE]`
+pub(crate) fn decrypt_chunk(key: &[u8; 32], chunk: &mut [u8]) -> Result<(), &'static str> {
+    let chunk_size = chunk.len();
+
+    if chunk_size <= METADATA_SIZE {
+        return Err("Chunk size mismatch");
+    }
+
+    let iv = chunk[(chunk_size - 48)..(chunk_size - 32)].to_vec();
+    let hash = chunk[(chunk_size - 32)..].to_vec();
+
+    let cipher = Aes256Cbc::new_from_slices(key, &iv).unwrap();
+
+    if cipher.decrypt(&mut chunk[0..(chunk_size - 48)]).is_err() {
+        return Err("Cannot decrypt chunk");
+    }
+
+    if Sha256::digest(&chunk[..chunk_size - METADATA_SIZE]).as_slice() != hash {
+        return Err("Hash mismatch");
+    }
+
+    Ok(())
+}
+
+/// Place of a container in the file and on Discord
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct ContainerLayout {
+    /// Offset of the first file byte of the container
+    pub(crate) cursor: u64,
+    /// Size of the uploaded container, whole chunks
+    pub(crate) size: u64,
+    /// End of the file range held by the container
+    pub(crate) end: u64,
+}
+
+pub(crate) fn container_count(file_size: u64, container_size: u64, chunk_size: u64) -> usize {
+    let chunk_count = (file_size / (chunk_size - METADATA_SIZE as u64)) + 1;
+
+    let chunks_per_container = container_size / chunk_size;
+
+    (chunk_count as f64 / chunks_per_container as f64).ceil() as usize
+}
+
+/// Layout of the container `index`, counted from 1.
+/// The last container is shortened to the chunks holding the end of the file.
+pub(crate) fn container_layout(file_size: u64, container_size: u32, chunk_size: u32, index: u32) -> ContainerLayout {
+    let chunk_size = chunk_size as u64;
+    let real_size = chunk_size - METADATA_SIZE as u64;
+    let chunks_per_container = container_size as u64 / chunk_size;
+
+    let cursor = (index as u64 - 1) * chunks_per_container * real_size;
+
+    let remaining_real_size = file_size - cursor;
+    let remaining_extra_padding = ((remaining_real_size / real_size) + 1) * METADATA_SIZE as u64;
+
+    let mut size = min(container_size as u64, remaining_real_size + remaining_extra_padding);
+
+    if !size.is_multiple_of(chunk_size) {
+        size += chunk_size - size % chunk_size;
+    }
+
+    let end = min(file_size, cursor + size - ((size / chunk_size) * METADATA_SIZE as u64));
+
+    ContainerLayout { cursor, size, end }
+}
+
+/// Number of file bytes held by a chunk of the container, 0 for padding chunks
+pub(crate) fn chunk_content_size(container: &Container, file_size: u64, chunk: u64) -> usize {
+    let real_size = container.chunk_size - METADATA_SIZE as u64;
+
+    let chunk_start = container.bytes_range[0] + chunk * real_size;
+    let chunk_stop = min(file_size, chunk_start + real_size);
+
+    chunk_stop.saturating_sub(chunk_start) as usize
+}
+
+/// Number of chunks of the container holding file bytes
+#[cfg(feature = "async")]
+pub(crate) fn content_chunk_count(container: &Container) -> u64 {
+    let real_size = container.chunk_size - METADATA_SIZE as u64;
+
+    min(container.chunk_count, (container.bytes_range[1] - container.bytes_range[0]).div_ceil(real_size))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chunk::{container_count, container_layout, decrypt_chunk, encrypt_chunk, ContainerLayout};
+    use crate::common::METADATA_SIZE;
+
+    #[test]
+    fn test_chunk_round_trip() {
+        let key = [7u8; 32];
+        let iv = [3u8; 16];
+
+        let mut chunk = vec![0u8; 16 * 1024];
+        for (i, byte) in chunk[..16 * 1024 - METADATA_SIZE].iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let content = chunk[..16 * 1024 - METADATA_SIZE].to_vec();
+
+        encrypt_chunk(&key, &iv, &mut chunk);
+        assert_ne!(&chunk[..content.len()], content.as_slice());
+
+        let mut tampered = chunk.clone();
+        tampered[100] ^= 1;
+        assert!(decrypt_chunk(&key, &mut tampered).is_err());
+        assert!(decrypt_chunk(&[8u8; 32], &mut chunk.clone()).is_err());
+
+        decrypt_chunk(&key, &mut chunk).unwrap();
+        assert_eq!(&chunk[..content.len()], content.as_slice());
+    }
+
+    #[test]
+    fn test_container_layout() {
+        let chunk_size = 1 << 16;
+        let real_size = chunk_size as u64 - METADATA_SIZE as u64;
+        let container_size = 4 * chunk_size;
+
+        // 2 full containers and 3 bytes
+        let file_size = 8 * real_size + 3;
+
+        assert_eq!(container_count(file_size, container_size as u64, chunk_size as u64), 3);
+
+        assert_eq!(container_layout(file_size, container_size, chunk_size, 1), ContainerLayout { cursor: 0, size: container_size as u64, end: 4 * real_size });
+        assert_eq!(container_layout(file_size, container_size, chunk_size, 3), ContainerLayout { cursor: 8 * real_size, size: chunk_size as u64, end: file_size });
+    }
+}
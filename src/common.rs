@@ -1,14 +1,34 @@
 use std::fs::File;
-use std::io::{Write};
-use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::str::FromStr;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use hex_buffer_serde::{Hex as _, HexForm};
 
+/// Size of the metadata appended to every encrypted chunk:
+/// 16 bytes of padding room, the 16 bytes IV and the 32 bytes SHA-256 of the plaintext.
+pub const METADATA_SIZE: usize = 64;
+
+/// Size of an encrypted chunk, metadata included, when the upload does not choose one
+pub const DEFAULT_CHUNK_SIZE: u32 = 1 << 16;
+
+/// Bounds of the chunk size of an upload
+pub const MIN_CHUNK_SIZE: u32 = 16 * 1024;
+pub const MAX_CHUNK_SIZE: u32 = 4 * 1024 * 1024;
+
+/// PBKDF2 iterations of containers that do not specify them
+pub const DEFAULT_KDF_ITERATIONS: u32 = 10000;
+
+/// Header of binary waterfalls, followed by the format version and the CBOR document
+pub const BINARY_WATERFALL_MAGIC: &[u8; 4] = b"DUSW";
+const BINARY_WATERFALL_VERSION: u8 = 1;
+
 pub trait FileWritable {
     fn write_to_file(&self, file_path: String);
 }
 
 pub trait FileReadable {
-    fn from_file(file_path: String) -> Self;
+    fn from_file(file_path: String) -> std::io::Result<Self>
+        where Self: Sized;
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -17,36 +37,298 @@ pub struct Waterfall {
     pub password: String,
     pub size: u64,
 
+    /// SHA-256 of the whole plaintext file.
+    /// Waterfalls produced by older versions do not carry it.
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "optional_hash")]
+    pub file_hash: Option<[u8; 32]>,
+
+    /// 0 for the waterfall of a file. A root pointer of level n describes
+    /// the binary encoding of a waterfall of level n - 1 instead.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub manifest_level: u32,
+
     pub containers: Vec<Container>,
 }
 
+impl Waterfall {
+    pub fn is_root_pointer(&self) -> bool {
+        self.manifest_level > 0
+    }
+
+    /// Earliest expiry of the storage urls, None if no url carries one
+    pub fn get_expiry(&self) -> Option<u64> {
+        self.containers.iter().filter_map(|c| c.get_expiry()).min()
+    }
+
+    /// Check that the containers are indexed from 1 without duplicates, that
+    /// their byte ranges tile `[0, size)` in index order without gaps or
+    /// overlaps and that each container holds the right number of chunks.
+    pub fn validate(&self) -> std::io::Result<()> {
+        if self.containers.is_empty() {
+            return Err(invalid_data("Waterfall has no container".to_string()));
+        }
+
+        let mut containers: Vec<&Container> = self.containers.iter().collect();
+        containers.sort_by_key(|c| c.index);
+
+        let mut cursor = 0u64;
+
+        for (position, container) in containers.into_iter().enumerate() {
+            if container.index != position as u32 + 1 {
+                return Err(invalid_data(format!("Unexpected container index {} at position {}", container.index, position + 1)));
+            }
+
+            let [start, end] = container.bytes_range;
+
+            if start > cursor {
+                return Err(invalid_data(format!("Gap in containers between bytes {} and {}", cursor, start)));
+            }
+
+            if start < cursor {
+                return Err(invalid_data(format!("Overlapping containers at bytes {}->{}", start, cursor)));
+            }
+
+            container.validate()?;
+
+            cursor = end;
+        }
+
+        if cursor != self.size {
+            return Err(invalid_data(format!("Containers cover {} bytes but file size is {}", cursor, self.size)));
+        }
+
+        Ok(())
+    }
+
+    /// Waterfalls written before containers carried their index are
+    /// numbered by byte range.
+    fn assign_missing_indexes(&mut self) {
+        if self.containers.iter().any(|c| c.index != 0) {
+            return;
+        }
+
+        self.containers.sort_by_key(|c| c.bytes_range[0]);
+
+        for (position, container) in self.containers.iter_mut().enumerate() {
+            container.index = position as u32 + 1;
+        }
+    }
+}
+
+impl Waterfall {
+    pub fn to_bytes(&self, format: WaterfallFormat) -> std::io::Result<Vec<u8>> {
+        match format {
+            WaterfallFormat::Json => Ok(serde_json::to_vec_pretty(&self)?),
+            WaterfallFormat::Binary => {
+                let mut bytes = BINARY_WATERFALL_MAGIC.to_vec();
+                bytes.push(BINARY_WATERFALL_VERSION);
+                ciborium::into_writer(&self, &mut bytes)
+                    .map_err(|err| std::io::Error::other(format!("Cannot encode waterfall: {}", err)))?;
+                Ok(bytes)
+            }
+        }
+    }
+
+    /// Decode a waterfall in any format
+    pub fn from_bytes(bytes: &[u8]) -> std::io::Result<Waterfall> {
+        let mut waterfall: Waterfall = match WaterfallFormat::detect(bytes) {
+            WaterfallFormat::Json => serde_json::from_slice(bytes)?,
+            WaterfallFormat::Binary => {
+                let version = bytes[BINARY_WATERFALL_MAGIC.len()];
+
+                if version != BINARY_WATERFALL_VERSION {
+                    return Err(invalid_data(format!("Unsupported binary waterfall version {}", version)));
+                }
+
+                ciborium::from_reader(&bytes[BINARY_WATERFALL_MAGIC.len() + 1..])
+                    .map_err(|err| invalid_data(format!("Invalid binary waterfall: {}", err)))?
+            }
+        };
+
+        waterfall.assign_missing_indexes();
+        waterfall.validate()?;
+
+        Ok(waterfall)
+    }
+
+    pub fn write_to_file_as(&self, file_path: String, format: WaterfallFormat) -> std::io::Result<()> {
+        let bytes = self.to_bytes(format)?;
+
+        File::create(file_path)?.write_all(&bytes)
+    }
+}
+
 impl FileWritable for Waterfall {
     fn write_to_file(&self, file_path: String) {
-        let mut file = File::create(file_path).unwrap();
-        file.write_all(serde_json::to_string_pretty(&self).unwrap().as_bytes()).unwrap();
+        self.write_to_file_as(file_path, WaterfallFormat::Json).unwrap();
     }
 }
 
 impl FileReadable for Waterfall {
-    fn from_file(file_path: String) -> Self {
-        let mut file = File::open(file_path).unwrap();
+    fn from_file(file_path: String) -> std::io::Result<Self> {
+        let mut bytes = Vec::new();
+        File::open(file_path)?.read_to_end(&mut bytes)?;
+
+        Waterfall::from_bytes(&bytes)
+    }
+}
 
-        serde_json::from_reader(&mut file).unwrap()
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WaterfallFormat {
+    /// Pretty printed JSON, the default
+    Json,
+    /// CBOR after a magic header, salts and hashes are stored as raw bytes
+    Binary,
+}
+
+impl WaterfallFormat {
+    pub fn detect(bytes: &[u8]) -> WaterfallFormat {
+        if bytes.len() > BINARY_WATERFALL_MAGIC.len() && bytes.starts_with(BINARY_WATERFALL_MAGIC) {
+            WaterfallFormat::Binary
+        } else {
+            WaterfallFormat::Json
+        }
+    }
+}
+
+impl FromStr for WaterfallFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(WaterfallFormat::Json),
+            "binary" | "cbor" => Ok(WaterfallFormat::Binary),
+            _ => Err(format!("Unknown waterfall format {:?}, expected json or binary", s)),
+        }
     }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Container {
+    /// Position of the container in the file, starting at 1
+    #[serde(default)]
+    pub index: u32,
+
     pub storage_url: String,
+    /// Message holding the attachment, not recorded by older uploads
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<u64>,
+    /// Channel of the message, not recorded by webhook and older uploads
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel_id: Option<u64>,
+    /// Name of the pool credential that uploaded the container
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credential: Option<String>,
+
     pub chunk_size: u64,
     pub chunk_count: u64,
 
     #[serde(with = "HexForm")]
     pub salt: [u8; 16],
 
+    /// PBKDF2-HMAC-SHA256 iterations used to derive the key from the password and salt
+    #[serde(default = "default_kdf_iterations")]
+    pub kdf_iterations: u32,
+
     pub bytes_range: [u64; 2],
 }
 
+fn default_kdf_iterations() -> u32 {
+    DEFAULT_KDF_ITERATIONS
+}
+
+fn default_chunk_size() -> u32 {
+    DEFAULT_CHUNK_SIZE
+}
+
+fn is_zero(value: &u32) -> bool {
+    *value == 0
+}
+
+impl Container {
+    /// Check that the byte range is well-formed and that the chunk count
+    /// matches what the uploader produces for that range.
+    pub fn validate(&self) -> std::io::Result<()> {
+        let [start, end] = self.bytes_range;
+
+        if end < start {
+            return Err(invalid_data(format!("Invalid container byte range {}->{}", start, end)));
+        }
+
+        if self.chunk_size <= METADATA_SIZE as u64 || self.chunk_size > MAX_CHUNK_SIZE as u64 {
+            return Err(invalid_data(format!("Invalid chunk size {}", self.chunk_size)));
+        }
+
+        let real_size = self.chunk_size - METADATA_SIZE as u64;
+        let length = end - start;
+
+        // the uploader always writes at least one chunk, and one extra
+        // empty chunk when the content is a multiple of the chunk real size
+        let min_chunks = length.div_ceil(real_size).max(1);
+        let max_chunks = length / real_size + 1;
+
+        if self.chunk_count < min_chunks || self.chunk_count > max_chunks {
+            return Err(invalid_data(format!("Container {}->{} has {} chunks, expected between {} and {}", start, end, self.chunk_count, min_chunks, max_chunks)));
+        }
+
+        Ok(())
+    }
+
+    /// Unix timestamp at which the storage url stops working,
+    /// read from the hexadecimal `ex` parameter of Discord CDN urls
+    pub fn get_expiry(&self) -> Option<u64> {
+        let url = reqwest::Url::parse(&self.storage_url).ok()?;
+
+        let (_, expiry) = url.query_pairs().find(|(key, _)| key == "ex")?;
+
+        u64::from_str_radix(&expiry, 16).ok()
+    }
+}
+
+/// Shared by the tests of every module, override the fields a test depends on
+#[cfg(test)]
+impl Container {
+    /// Container of `range` with the default chunk size and the fewest chunks,
+    /// a null salt and one KDF iteration, at an unreachable url
+    pub(crate) fn test_fixture(index: u32, range: [u64; 2]) -> Container {
+        let chunk_size = DEFAULT_CHUNK_SIZE as u64;
+        let real_size = chunk_size - METADATA_SIZE as u64;
+
+        Container {
+            index,
+            storage_url: "http://127.0.0.1:1/data.enc".to_string(),
+            message_id: None,
+            channel_id: None,
+            credential: None,
+            chunk_size,
+            chunk_count: (range[1] - range[0]).div_ceil(real_size).max(1),
+            salt: [0u8; 16],
+            kdf_iterations: 1,
+            bytes_range: range,
+        }
+    }
+}
+
+fn invalid_data(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+mod optional_hash {
+    use super::*;
+
+    #[derive(Serialize, Deserialize)]
+    struct Hash(#[serde(with = "HexForm")] [u8; 32]);
+
+    pub fn serialize<S: Serializer>(hash: &Option<[u8; 32]>, serializer: S) -> Result<S::Ok, S::Error> {
+        hash.map(Hash).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<[u8; 32]>, D::Error> {
+        Ok(Option::<Hash>::deserialize(deserializer)?.map(|hash| hash.0))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Subscription {
     Free,
     Basic,
@@ -64,6 +346,26 @@ impl Subscription {
             Self::Boost => 500 * 1024 * 1024,
         }
     }
+
+    /// Largest container of whole chunks of `chunk_size` allowed by the subscription
+    pub fn get_container_size(&self, chunk_size: u32) -> usize {
+        let chunk_size = chunk_size as usize;
+        self.get_max_chunk_upload_size() / chunk_size * chunk_size
+    }
+}
+
+impl FromStr for Subscription {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "free" => Ok(Self::Free),
+            "basic" => Ok(Self::Basic),
+            "classic" => Ok(Self::Classic),
+            "boost" => Ok(Self::Boost),
+            _ => Err(format!("Unknown subscription {:?}, expected free, basic, classic or boost", s)),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -72,6 +374,10 @@ pub struct ResumableFileUpload {
     pub(crate) file_size: u64,
 
     pub(crate) container_size: u32,
+
+    #[serde(default = "default_chunk_size")]
+    pub(crate) chunk_size: u32,
+
     pub(crate) remaining_indexes: Vec<u32>,
     pub(crate) containers: Vec<Container>,
 
@@ -89,9 +395,190 @@ impl FileWritable for ResumableFileUpload {
 }
 
 impl FileReadable for ResumableFileUpload {
-    fn from_file(file_path: String) -> Self {
-        let mut file = File::open(file_path).unwrap();
+    fn from_file(file_path: String) -> std::io::Result<Self> {
+        let mut file = File::open(file_path)?;
+
+        Ok(serde_json::from_reader(&mut file)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{Container, Waterfall, WaterfallFormat, DEFAULT_CHUNK_SIZE, DEFAULT_KDF_ITERATIONS, METADATA_SIZE};
+
+    const REAL_SIZE: u64 = DEFAULT_CHUNK_SIZE as u64 - METADATA_SIZE as u64;
+
+    fn container(index: u32, start: u64, end: u64, chunk_count: u64) -> Container {
+        Container { chunk_count, ..Container::test_fixture(index, [start, end]) }
+    }
+
+    fn waterfall(size: u64, containers: Vec<Container>) -> Waterfall {
+        Waterfall {
+            filename: "file".to_string(),
+            password: String::new(),
+            size,
+            file_hash: None,
+            manifest_level: 0,
+            containers,
+        }
+    }
+
+    #[test]
+    fn test_valid_waterfall() {
+        let size = 10 * REAL_SIZE + 5;
+
+        let w = waterfall(size, vec![
+            container(2, 4 * REAL_SIZE, 8 * REAL_SIZE, 4),
+            container(1, 0, 4 * REAL_SIZE, 4),
+            container(3, 8 * REAL_SIZE, size, 3),
+        ]);
+
+        assert!(w.validate().is_ok());
+
+        // an exact multiple of the real chunk size gets a trailing empty chunk
+        let w = waterfall(2 * REAL_SIZE, vec![container(1, 0, 2 * REAL_SIZE, 3)]);
 
-        serde_json::from_reader(&mut file).unwrap()
+        assert!(w.validate().is_ok());
+
+        let w = waterfall(0, vec![container(1, 0, 0, 1)]);
+
+        assert!(w.validate().is_ok());
+    }
+
+    #[test]
+    fn test_invalid_coverage() {
+        let gap = waterfall(3 * REAL_SIZE, vec![
+            container(1, 0, REAL_SIZE, 1),
+            container(2, 2 * REAL_SIZE, 3 * REAL_SIZE, 2),
+        ]);
+        assert!(gap.validate().is_err());
+
+        let overlap = waterfall(3 * REAL_SIZE, vec![
+            container(1, 0, 2 * REAL_SIZE, 2),
+            container(2, REAL_SIZE, 3 * REAL_SIZE, 3),
+        ]);
+        assert!(overlap.validate().is_err());
+
+        let short = waterfall(3 * REAL_SIZE, vec![container(1, 0, 2 * REAL_SIZE, 2)]);
+        assert!(short.validate().is_err());
+
+        assert!(waterfall(0, vec![]).validate().is_err());
+    }
+
+    #[test]
+    fn test_invalid_chunk_count() {
+        let w = waterfall(3 * REAL_SIZE + 1, vec![container(1, 0, 3 * REAL_SIZE + 1, 3)]);
+        assert!(w.validate().is_err());
+
+        let w = waterfall(3 * REAL_SIZE + 1, vec![container(1, 0, 3 * REAL_SIZE + 1, 5)]);
+        assert!(w.validate().is_err());
+    }
+
+    #[test]
+    fn test_container_indexes() {
+        let duplicate = waterfall(2 * REAL_SIZE, vec![
+            container(1, 0, REAL_SIZE, 1),
+            container(1, REAL_SIZE, 2 * REAL_SIZE, 2),
+        ]);
+        assert!(duplicate.validate().is_err());
+
+        // index order must follow byte order
+        let swapped = waterfall(2 * REAL_SIZE, vec![
+            container(2, 0, REAL_SIZE, 1),
+            container(1, REAL_SIZE, 2 * REAL_SIZE, 2),
+        ]);
+        assert!(swapped.validate().is_err());
+
+        let mut legacy = waterfall(2 * REAL_SIZE, vec![
+            container(0, REAL_SIZE, 2 * REAL_SIZE, 2),
+            container(0, 0, REAL_SIZE, 1),
+        ]);
+        legacy.assign_missing_indexes();
+
+        assert_eq!(legacy.containers[0].bytes_range[0], 0);
+        assert_eq!(legacy.containers[0].index, 1);
+        assert_eq!(legacy.containers[1].index, 2);
+        assert!(legacy.validate().is_ok());
+    }
+
+    #[test]
+    fn test_file_hash_serialization() {
+        let mut w = waterfall(0, vec![container(1, 0, 0, 1)]);
+
+        let json = serde_json::to_string(&w).unwrap();
+        assert!(!json.contains("file_hash"));
+        assert!(serde_json::from_str::<Waterfall>(&json).unwrap().file_hash.is_none());
+
+        w.file_hash = Some([0xab; 32]);
+
+        let json = serde_json::to_string(&w).unwrap();
+        assert!(json.contains(&"ab".repeat(32)));
+        assert_eq!(serde_json::from_str::<Waterfall>(&json).unwrap().file_hash, Some([0xab; 32]));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_default_kdf_iterations() {
+        let json = r#"{"storage_url":"","chunk_size":65536,"chunk_count":1,"salt":"00000000000000000000000000000000","bytes_range":[0,0]}"#;
+
+        let legacy: Container = serde_json::from_str(json).unwrap();
+        assert_eq!(legacy.kdf_iterations, DEFAULT_KDF_ITERATIONS);
+
+        let mut c = container(1, 0, 0, 1);
+        c.kdf_iterations = 200_000;
+
+        let json = serde_json::to_string(&c).unwrap();
+        assert_eq!(serde_json::from_str::<Container>(&json).unwrap().kdf_iterations, 200_000);
+    }
+
+    #[test]
+    fn test_expiry() {
+        let mut first = container(1, 0, REAL_SIZE, 1);
+        first.storage_url = "https://cdn.discordapp.com/attachments/1/2/file?ex=65a1b2c3&is=658f3dc3&hm=ab12".to_string();
+
+        let mut second = container(2, REAL_SIZE, 2 * REAL_SIZE, 2);
+        second.storage_url = "https://cdn.discordapp.com/attachments/1/3/file?ex=65a1b2c4".to_string();
+
+        assert_eq!(first.get_expiry(), Some(0x65a1b2c3));
+        assert_eq!(waterfall(2 * REAL_SIZE, vec![second, first]).get_expiry(), Some(0x65a1b2c3));
+
+        let legacy = container(1, 0, REAL_SIZE, 1);
+        assert_eq!(legacy.get_expiry(), None);
+        assert_eq!(waterfall(REAL_SIZE, vec![legacy]).get_expiry(), None);
+    }
+
+    #[test]
+    fn test_binary_waterfall() {
+        let mut w = waterfall(5 * REAL_SIZE, vec![
+            container(1, 0, 4 * REAL_SIZE, 4),
+            container(2, 4 * REAL_SIZE, 5 * REAL_SIZE, 2),
+        ]);
+        w.file_hash = Some([7u8; 32]);
+        w.containers[0].salt = [3u8; 16];
+        w.containers[1].storage_url = "https://cdn.discordapp.com/attachments/1/2/data.enc?ex=65a1b2c3".to_string();
+
+        let json = w.to_bytes(WaterfallFormat::Json).unwrap();
+        let binary = w.to_bytes(WaterfallFormat::Binary).unwrap();
+
+        assert_eq!(WaterfallFormat::detect(&json), WaterfallFormat::Json);
+        assert_eq!(WaterfallFormat::detect(&binary), WaterfallFormat::Binary);
+        assert!(binary.len() < json.len());
+
+        for bytes in [&json, &binary] {
+            let decoded = Waterfall::from_bytes(bytes).unwrap();
+            assert_eq!(serde_json::to_value(&decoded).unwrap(), serde_json::to_value(&w).unwrap());
+        }
+
+        // waterfalls of files keep the previous layout
+        assert!(!String::from_utf8(json).unwrap().contains("manifest_level"));
+
+        w.manifest_level = 2;
+        let root = Waterfall::from_bytes(&w.to_bytes(WaterfallFormat::Binary).unwrap()).unwrap();
+        assert!(root.is_root_pointer());
+        assert_eq!(root.manifest_level, 2);
+
+        let mut unknown_version = binary.clone();
+        unknown_version[4] = 2;
+        assert!(Waterfall::from_bytes(&unknown_version).is_err());
+        assert!(Waterfall::from_bytes(&binary[..binary.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,153 @@
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Condvar, Mutex};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TransferState {
+    #[default]
+    Running,
+    Paused,
+    Cancelled,
+}
+
+// Transfer control
+// Shared between the caller and every worker of a transfer,
+// workers hold between containers while paused
+// and stop as soon as possible once cancelled
+#[derive(Clone, Debug, Default)]
+pub struct TransferControl {
+    state: Arc<(Mutex<TransferState>, Condvar)>,
+}
+
+impl TransferControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.set_state(TransferState::Cancelled);
+    }
+
+    /// Has no effect on a cancelled transfer
+    pub fn pause(&self) {
+        let (lock, _) = &*self.state;
+
+        let mut state = lock.lock().unwrap();
+
+        if *state == TransferState::Running {
+            *state = TransferState::Paused;
+        }
+    }
+
+    /// Has no effect on a cancelled transfer
+    pub fn resume(&self) {
+        let (lock, condvar) = &*self.state;
+
+        let mut state = lock.lock().unwrap();
+
+        if *state == TransferState::Paused {
+            *state = TransferState::Running;
+            condvar.notify_all();
+        }
+    }
+
+    pub fn get_state(&self) -> TransferState {
+        *self.state.0.lock().unwrap()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.get_state() == TransferState::Cancelled
+    }
+
+    /// Block while the transfer is paused.
+    /// Returns false if the transfer is cancelled.
+    pub fn wait_while_paused(&self) -> bool {
+        let (lock, condvar) = &*self.state;
+
+        let mut state = lock.lock().unwrap();
+
+        while *state == TransferState::Paused {
+            state = condvar.wait(state).unwrap();
+        }
+
+        *state != TransferState::Cancelled
+    }
+
+    /// Error to return from the transfer loops once cancelled.
+    /// Not `ErrorKind::Interrupted`, which `read_to_end`, `read_exact`
+    /// and `io::copy` retry forever.
+    pub(crate) fn check_cancelled(&self) -> std::io::Result<()> {
+        if self.is_cancelled() {
+            return Err(std::io::Error::other(Cancelled));
+        }
+
+        Ok(())
+    }
+
+    fn set_state(&self, new_state: TransferState) {
+        let (lock, condvar) = &*self.state;
+
+        *lock.lock().unwrap() = new_state;
+
+        condvar.notify_all();
+    }
+}
+
+/// Error of a cancelled transfer, wrapped in an `io::Error` of kind `Other`
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl Cancelled {
+    pub fn is_cancelled(err: &std::io::Error) -> bool {
+        err.get_ref().is_some_and(|inner| inner.is::<Cancelled>())
+    }
+}
+
+impl Display for Cancelled {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Transfer cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+    use crate::control::{Cancelled, TransferControl, TransferState};
+
+    #[test]
+    fn test_pause_resume() {
+        let control = TransferControl::new();
+
+        control.pause();
+        assert_eq!(control.get_state(), TransferState::Paused);
+
+        let worker_control = control.clone();
+        let worker = thread::spawn(move || worker_control.wait_while_paused());
+
+        thread::sleep(Duration::from_millis(20));
+        assert!(!worker.is_finished());
+
+        control.resume();
+        assert!(worker.join().unwrap());
+    }
+
+    #[test]
+    fn test_cancel_releases_paused_workers() {
+        let control = TransferControl::new();
+
+        control.pause();
+
+        let worker_control = control.clone();
+        let worker = thread::spawn(move || worker_control.wait_while_paused());
+
+        control.cancel();
+        assert!(!worker.join().unwrap());
+
+        // a cancelled transfer cannot be resumed
+        control.resume();
+        assert!(control.is_cancelled());
+        assert!(Cancelled::is_cancelled(&control.check_cancelled().unwrap_err()));
+    }
+}
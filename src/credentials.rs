@@ -0,0 +1,296 @@
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+
+// Credential pool
+// Tokens and the channels they upload to, shared by the upload workers.
+// Each container is uploaded with the least busy credential that has a free
+// slot and is not rate limited. A credential rejected by Discord (401/403)
+// is disabled for the rest of the pool's life, a rate limited one (429) is
+// skipped until the delay given by Discord has passed.
+
+/// Wait used when no `Retry-After` is given with a 429
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+/// Longest wait between two checks of the pool by an async upload
+#[cfg(feature = "async")]
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Credential {
+    /// Recorded in the waterfall for each container, never the token
+    pub name: String,
+    pub token: String,
+    pub channel_id: u64,
+    /// Containers uploaded at the same time with this credential
+    pub concurrency: usize,
+}
+
+impl Credential {
+    /// Unnamed credential without concurrency limit
+    pub fn new(token: String, channel_id: u64) -> Credential {
+        Credential { name: String::new(), token, channel_id, concurrency: usize::MAX }
+    }
+
+    pub fn named(name: String, token: String, channel_id: u64, concurrency: usize) -> Credential {
+        Credential { name, token, channel_id, concurrency: concurrency.max(1) }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CredentialStatus {
+    pub name: String,
+    pub channel_id: u64,
+    /// Containers being uploaded with the credential
+    pub active: usize,
+    pub uploaded: u64,
+    pub rate_limited: u64,
+    /// Reason the credential was disabled
+    pub disabled: Option<String>,
+}
+
+struct CredentialState {
+    credential: Credential,
+    active: usize,
+    uploaded: u64,
+    rate_limited: u64,
+    retry_at: Option<Instant>,
+    disabled: Option<String>,
+}
+
+impl CredentialState {
+    fn is_ready(&self, now: Instant) -> bool {
+        self.disabled.is_none()
+            && self.active < self.credential.concurrency
+            && self.retry_at.is_none_or(|retry_at| retry_at <= now)
+    }
+}
+
+/// Outcome of an attempt to take a credential
+pub(crate) enum Acquire {
+    Ready(CredentialLease),
+    /// Every usable credential is busy or rate limited, try again within this delay
+    Wait(Duration),
+    /// Every credential is disabled
+    Exhausted,
+}
+
+#[derive(Clone)]
+pub struct CredentialPool {
+    state: Arc<(Mutex<Vec<CredentialState>>, Condvar)>,
+}
+
+impl CredentialPool {
+    pub fn new(credentials: Vec<Credential>) -> CredentialPool {
+        let states = credentials.into_iter()
+            .map(|credential| CredentialState { credential, active: 0, uploaded: 0, rate_limited: 0, retry_at: None, disabled: None })
+            .collect();
+
+        CredentialPool { state: Arc::new((Mutex::new(states), Condvar::new())) }
+    }
+
+    pub fn get_status(&self) -> Vec<CredentialStatus> {
+        self.state.0.lock().unwrap().iter()
+            .map(|state| CredentialStatus {
+                name: state.credential.name.clone(),
+                channel_id: state.credential.channel_id,
+                active: state.active,
+                uploaded: state.uploaded,
+                rate_limited: state.rate_limited,
+                disabled: state.disabled.clone(),
+            })
+            .collect()
+    }
+
+    pub(crate) fn try_acquire(&self) -> Acquire {
+        let mut states = self.state.0.lock().unwrap();
+        let now = Instant::now();
+
+        let ready = states.iter().enumerate()
+            .filter(|(_, state)| state.is_ready(now))
+            .min_by_key(|(_, state)| state.active)
+            .map(|(index, _)| index);
+
+        if let Some(index) = ready {
+            states[index].active += 1;
+
+            return Acquire::Ready(CredentialLease {
+                pool: self.clone(),
+                index,
+                credential: states[index].credential.clone(),
+            });
+        }
+
+        let enabled: Vec<_> = states.iter().filter(|state| state.disabled.is_none()).collect();
+
+        if enabled.is_empty() {
+            return Acquire::Exhausted;
+        }
+
+        // a free slot comes back with a release, a rate limit ends at its deadline
+        let wait = enabled.iter()
+            .filter(|state| state.active < state.credential.concurrency)
+            .filter_map(|state| state.retry_at)
+            .map(|retry_at| retry_at.saturating_duration_since(now))
+            .min()
+            .unwrap_or(Duration::from_secs(1));
+
+        Acquire::Wait(wait)
+    }
+
+    /// Wait for a credential, `None` once every credential is disabled
+    pub(crate) fn acquire(&self) -> Option<CredentialLease> {
+        loop {
+            match self.try_acquire() {
+                Acquire::Ready(lease) => return Some(lease),
+                Acquire::Exhausted => return None,
+                Acquire::Wait(wait) => {
+                    let states = self.state.0.lock().unwrap();
+                    let _ = self.state.1.wait_timeout(states, wait).unwrap();
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) async fn acquire_async(&self) -> Option<CredentialLease> {
+        loop {
+            match self.try_acquire() {
+                Acquire::Ready(lease) => return Some(lease),
+                Acquire::Exhausted => return None,
+                Acquire::Wait(wait) => tokio::time::sleep(wait.min(POLL_INTERVAL)).await,
+            }
+        }
+    }
+
+    fn update<F: FnOnce(&mut CredentialState)>(&self, index: usize, f: F) {
+        f(&mut self.state.0.lock().unwrap()[index]);
+
+        self.state.1.notify_all();
+    }
+}
+
+/// A slot of a credential, given back when dropped
+pub(crate) struct CredentialLease {
+    pool: CredentialPool,
+    index: usize,
+    pub(crate) credential: Credential,
+}
+
+impl CredentialLease {
+    pub(crate) fn uploaded(&self) {
+        self.pool.update(self.index, |state| state.uploaded += 1);
+    }
+
+    /// Disable the credential after an authentication error,
+    /// or delay its next use after a rate limit
+    pub(crate) fn failed(&self, err: &std::io::Error) {
+        if err.kind() == std::io::ErrorKind::PermissionDenied {
+            let reason = err.to_string();
+            self.pool.update(self.index, |state| state.disabled = Some(reason));
+        } else if let Some(limit) = err.get_ref().and_then(|inner| inner.downcast_ref::<RateLimited>()) {
+            let retry_at = Instant::now() + limit.retry_after;
+
+            self.pool.update(self.index, |state| {
+                state.rate_limited += 1;
+                state.retry_at = Some(state.retry_at.map_or(retry_at, |current| current.max(retry_at)));
+            });
+        }
+    }
+}
+
+impl Drop for CredentialLease {
+    fn drop(&mut self) {
+        self.pool.update(self.index, |state| state.active -= 1);
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct RateLimited {
+    pub(crate) retry_after: Duration,
+}
+
+impl Display for RateLimited {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Rate limited by Discord, retry after {:?}", self.retry_after)
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+/// Error of a Discord API response: `PermissionDenied` for a rejected token,
+/// `RateLimited` for a rate limit, other failures as is
+pub(crate) fn check_status(status: StatusCode, headers: &HeaderMap) -> std::io::Result<()> {
+    if status.is_success() {
+        return Ok(());
+    }
+
+    match status {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+            Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, format!("Credential rejected by Discord ({})", status)))
+        }
+        StatusCode::TOO_MANY_REQUESTS => {
+            let retry_after = headers.get("retry-after")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse::<f64>().ok())
+                .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+                .map(Duration::from_secs_f64)
+                .unwrap_or(DEFAULT_RETRY_AFTER);
+
+            Err(std::io::Error::other(RateLimited { retry_after }))
+        }
+        _ => Err(std::io::Error::other(format!("Discord answered {}", status))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use reqwest::header::{HeaderMap, HeaderValue};
+    use reqwest::StatusCode;
+    use crate::credentials::{check_status, Acquire, Credential, CredentialPool};
+
+    #[test]
+    fn test_credential_pool() {
+        let pool = CredentialPool::new(vec![
+            Credential::named("a".to_string(), "token-a".to_string(), 1, 1),
+            Credential::named("b".to_string(), "token-b".to_string(), 2, 2),
+        ]);
+
+        // least busy first, within the concurrency of each credential
+        let first = pool.acquire().unwrap();
+        let second = pool.acquire().unwrap();
+        let third = pool.acquire().unwrap();
+        assert_eq!(first.credential.name, "a");
+        assert_eq!(second.credential.name, "b");
+        assert_eq!(third.credential.name, "b");
+        assert!(matches!(pool.try_acquire(), Acquire::Wait(_)));
+
+        drop(second);
+        third.uploaded();
+
+        let mut headers = HeaderMap::new();
+        headers.insert("retry-after", HeaderValue::from_static("60"));
+        third.failed(&check_status(StatusCode::TOO_MANY_REQUESTS, &headers).unwrap_err());
+        drop(third);
+
+        // b is rate limited, a is busy
+        assert!(matches!(pool.try_acquire(), Acquire::Wait(wait) if wait > Duration::from_secs(50)));
+
+        first.failed(&check_status(StatusCode::UNAUTHORIZED, &HeaderMap::new()).unwrap_err());
+        drop(first);
+
+        let status = pool.get_status();
+        assert!(status[0].disabled.is_some());
+        assert_eq!((status[1].active, status[1].uploaded, status[1].rate_limited), (0, 1, 1));
+
+        assert!(check_status(StatusCode::OK, &HeaderMap::new()).is_ok());
+        assert_eq!(check_status(StatusCode::BAD_GATEWAY, &HeaderMap::new()).unwrap_err().kind(), std::io::ErrorKind::Other);
+
+        let single = CredentialPool::new(vec![Credential::new("token".to_string(), 1)]);
+        single.acquire().unwrap().failed(&check_status(StatusCode::FORBIDDEN, &HeaderMap::new()).unwrap_err());
+        assert!(single.acquire().is_none());
+    }
+}
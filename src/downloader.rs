@@ -1,24 +1,27 @@
 use std::cmp::{min};
-use std::fs::File;
-use std::io::{Read, Write};
-use aes::Aes256;
-use block_modes::block_padding::Pkcs7;
-use block_modes::{BlockMode, Cbc};
-use hmac::Hmac;
-use pbkdf2::pbkdf2;
-use reqwest::blocking::{Response};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread::{scope, sleep};
+use std::time::Duration;
+use reqwest::blocking::{Client, Response};
 use reqwest::{StatusCode};
 use sha2::{Digest, Sha256};
-use crate::common::{Container, Waterfall};
-use crate::http_client::create_client;
-use crate::signal::{ReportSignal, ProgressionRange, LinearPartSignal, PartProgression};
+use crate::cache::ChunkCache;
+use crate::chunk::{chunk_content_size, decrypt_chunk, derive_key};
+use crate::common::{Container, Waterfall, METADATA_SIZE};
+use crate::control::TransferControl;
+use crate::throttle::{throttle, RateLimiter};
+use crate::http_client::default_client;
+use crate::share::MAX_PAYLOAD_SIZE;
+use crate::signal::{ReportSignal, ProgressionRange, LinearPartSignal, PartProgression, MultiSignal, TransferEvent, ContainerEvents};
 
-type Aes256Cbc = Cbc<Aes256, Pkcs7>;
-
-const METADATA_SIZE: usize = 64;
+pub(crate) const DOWNLOAD_RETRIES: u32 = 3;
 
 pub trait Downloader {
-    fn download_file(&self, file_path: String);
+    fn download_file(&self, file_path: String) -> std::io::Result<()>;
 }
 
 pub trait WaterfallDownloader {
@@ -32,7 +35,7 @@ pub trait ByteRangeDownloader {
 }
 
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct DownloadProgressionSignal {
     signal: Option<Box<dyn ReportSignal<ProgressionRange<u64>>>>,
 }
@@ -61,6 +64,13 @@ pub struct FileDownloader {
     password: String,
 
     signal: DownloadProgressionSignal,
+    events: MultiSignal<TransferEvent>,
+    control: TransferControl,
+    rate_limiter: Option<RateLimiter>,
+    threads: usize,
+    cache: Option<ChunkCache>,
+    // the default client is built by the first stream, it cannot be built on an async runtime
+    client: Option<Client>,
 }
 
 unsafe impl Send for FileDownloader {
@@ -76,6 +86,12 @@ impl WaterfallDownloader for FileDownloader {
             password,
 
             signal: DownloadProgressionSignal::new(),
+            events: MultiSignal::new(),
+            control: TransferControl::new(),
+            rate_limiter: None,
+            threads: 1,
+            cache: None,
+            client: None,
         }
     }
 }
@@ -91,15 +107,111 @@ impl FileDownloader {
         self.signal.signal = Some(Box::new(signal.clone()));
     }
 
+    /// Can be called several times to report events to several signals
+    pub fn with_events<S>(&mut self, signal: &S)
+        where S: ReportSignal<TransferEvent> + Clone + 'static {
+        self.events.add_signal(Box::new(signal.clone()));
+    }
+
+    /// Downloads hold between containers while paused, downloads and range
+    /// readers fail with a `Cancelled` error once cancelled
+    pub fn with_control(&mut self, control: &TransferControl) {
+        self.control = control.clone();
+    }
+
+    /// Limit the download rate, on top of `RateLimiter::global()`
+    pub fn with_rate_limiter(&mut self, rate_limiter: &RateLimiter) {
+        self.rate_limiter = Some(rate_limiter.clone());
+    }
+
+    /// Number of containers downloaded at the same time, 1 by default
+    pub fn with_threads(&mut self, threads: usize) {
+        self.threads = threads.max(1);
+    }
+
+    /// Read chunks from the cache when possible, and store the downloaded ones
+    pub fn with_cache(&mut self, cache: &ChunkCache) {
+        self.cache = Some(cache.clone());
+    }
+
+    /// Client shared by the containers and ranges, see `HttpConfig::build`
+    pub fn with_client(&mut self, client: &Client) {
+        self.client = Some(client.clone());
+    }
+
+    /// Replace a root pointer by the waterfall of the file, downloading
+    /// one manifest per level. The password applies to every level
+    /// unless a manifest embeds its own.
+    pub fn resolve_root_pointer(&mut self) -> std::io::Result<()> {
+        while self.waterfall.is_root_pointer() {
+            let mut manifest_downloader = FileDownloader::from_waterfall(self.waterfall.clone());
+            manifest_downloader.set_password(self.password.clone());
+            manifest_downloader.control = self.control.clone();
+            manifest_downloader.rate_limiter = self.rate_limiter.clone();
+            manifest_downloader.cache = self.cache.clone();
+            manifest_downloader.client = self.client.clone();
+
+            check_manifest_size(&self.waterfall)?;
+
+            let mut bytes = Vec::new();
+            manifest_downloader.get_range(0, self.waterfall.size).read_to_end(&mut bytes)?;
+
+            let waterfall = parse_manifest(&self.waterfall, &bytes)?;
+
+            if !waterfall.password.is_empty() {
+                self.password = waterfall.password.clone();
+            }
+
+            self.waterfall = waterfall;
+        }
+
+        Ok(())
+    }
+
+    pub fn get_waterfall(&self) -> &Waterfall {
+        &self.waterfall
+    }
+
     pub fn get_container_downloader(&self, container: Container) -> ContainerDownloader {
-        ContainerDownloader::new(container.clone(), self.waterfall.size, self.password.clone())
+        let mut downloader = ContainerDownloader::new(container.clone(), self.waterfall.size, self.password.clone());
+
+        downloader.rate_limiter = self.rate_limiter.clone();
+        downloader.events = self.events.clone();
+        downloader.cache = self.cache.clone();
+        downloader.client = self.client.clone();
+
+        downloader
     }
 
     pub fn get_range(&self, start: u64, end: u64) -> ByteRangeStreamDownloader {
-        let downloader = ByteRangeStreamDownloader::new([start, end], self.clone());
+        ByteRangeStreamDownloader::new([start, end], self.clone())
+    }
+}
 
-        downloader
+/// Reject root pointers announcing a manifest too large to be held in memory
+pub(crate) fn check_manifest_size(root_pointer: &Waterfall) -> std::io::Result<()> {
+    if root_pointer.size > MAX_PAYLOAD_SIZE {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Manifest of {} bytes is too large", root_pointer.size)));
+    }
+
+    Ok(())
+}
+
+/// Waterfall held by the manifest a root pointer points to
+pub(crate) fn parse_manifest(root_pointer: &Waterfall, bytes: &[u8]) -> std::io::Result<Waterfall> {
+    if let Some(expected_hash) = root_pointer.file_hash {
+        if Sha256::digest(bytes).as_slice() != expected_hash {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Manifest hash mismatch"));
+        }
     }
+
+    let waterfall = Waterfall::from_bytes(bytes)?;
+
+    if waterfall.manifest_level + 1 != root_pointer.manifest_level {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Manifest of level {} found under a root pointer of level {}", waterfall.manifest_level, root_pointer.manifest_level)));
+    }
+
+    Ok(waterfall)
 }
 
 #[derive(Clone)]
@@ -107,27 +219,37 @@ pub struct ContainerDownloader {
     container: Container,
     key: [u8; 32],
     file_size: u64,
+
+    rate_limiter: Option<RateLimiter>,
+    events: MultiSignal<TransferEvent>,
+    worker: usize,
+    cache: Option<ChunkCache>,
+    client: Option<Client>,
 }
 
 impl ContainerDownloader {
-    fn hash_key(encryption_password: String, salt: [u8; 16]) -> [u8; 32] {
-        let mut key = [0u8; 32];
-        pbkdf2::<Hmac<Sha256>>(encryption_password.as_bytes(), &salt, 10000, &mut key);
-        key
-    }
-
     pub fn new(container: Container, file_size: u64, encryption_password: String) -> Self {
-        let key = Self::hash_key(encryption_password, container.salt);
+        let key = derive_key(&encryption_password, &container.salt, container.kdf_iterations);
 
         ContainerDownloader {
             container,
             key,
             file_size,
+            rate_limiter: None,
+            events: MultiSignal::new(),
+            worker: 0,
+            cache: None,
+            client: None,
         }
     }
 
     pub fn get_byte_stream(&self, chunk_offset: u64, count: usize) -> Result<ByteStream, &str> {
-        ByteStream::new(self.container.clone(), self.key.clone(), self.file_size, chunk_offset, count)
+        let mut stream = ByteStream::open(self.container.clone(), self.key, self.file_size, chunk_offset, count, self.cache.clone(), self.client.clone())?;
+
+        stream.rate_limiter = self.rate_limiter.clone();
+        stream.events = self.container_events();
+
+        Ok(stream)
     }
 
     pub fn get_chunks(&self, chunk_offset: u64, count: usize) -> Result<Vec<Vec<u8>>, &str> {
@@ -138,13 +260,21 @@ impl ContainerDownloader {
         for _i in 0..count {
             let mut chunk: Vec<u8> = vec![0; self.container.chunk_size as usize - METADATA_SIZE];
 
-            downloader.read(&mut chunk).expect("TODO: panic message");
+            downloader.read_exact(&mut chunk).expect("TODO: panic message");
 
             chunks.push(chunk);
         }
 
         Ok(chunks.clone())
     }
+
+    fn container_events(&self) -> ContainerEvents {
+        ContainerEvents {
+            signal: self.events.clone(),
+            worker: self.worker,
+            index: self.container.index,
+        }
+    }
 }
 
 pub struct ByteStream {
@@ -159,94 +289,139 @@ pub struct ByteStream {
     buffer: Vec<u8>,
     buffer_cursor: usize,
 
-    response: Response,
+    // positioned at the current chunk, opened at the first chunk missing from the cache
+    response: Option<Response>,
+    cache: Option<ChunkCache>,
+    client: Client,
+
+    rate_limiter: Option<RateLimiter>,
+    events: ContainerEvents,
 }
 
 impl ByteStream {
     pub fn new(container: Container, key: [u8; 32], file_size: u64, chunk_offset: u64, count: usize) -> Result<Self, &'static str> {
-        let range_start = chunk_offset * container.chunk_size;
-        let range_stop = range_start + (count as u64 * container.chunk_size);
+        Self::open(container, key, file_size, chunk_offset, count, None, None)
+    }
+
+    fn open(container: Container, key: [u8; 32], file_size: u64, chunk_offset: u64, count: usize, cache: Option<ChunkCache>, client: Option<Client>) -> Result<Self, &'static str> {
+        let client = client.unwrap_or_else(default_client);
+
+        let chunk_size = container.chunk_size;
+
+        let events = ContainerEvents { signal: MultiSignal::new(), worker: 0, index: container.index };
+
+        let mut stream = Self { container, key, file_size, chunk_offset, count, current_chunk: 0, buffer: vec![0; chunk_size as usize], buffer_cursor: chunk_size as usize, response: None, cache, client, rate_limiter: None, events };
+
+        // fail early when the first chunk has to be downloaded
+        let first_cached = count > 0 && stream.cache.as_ref()
+            .is_some_and(|cache| cache.contains(&stream.container, chunk_offset));
+
+        if !first_cached {
+            stream.open_response()?;
+        }
+
+        Ok(stream)
+    }
 
-        let response = create_client().get(container.storage_url.clone())
+    /// Request the chunks from the current one to the last one
+    fn open_response(&mut self) -> Result<(), &'static str> {
+        let range_start = (self.chunk_offset + self.current_chunk) * self.container.chunk_size;
+        let range_stop = (self.chunk_offset + self.count as u64) * self.container.chunk_size;
+
+        let response = self.client.get(self.container.storage_url.clone())
             .header("User-Agent", "Mozilla/5.0")
             .header("Range", format!("bytes={}-{}", range_start, range_stop))
             .send()
-            .unwrap();
+            .map_err(|_| "Cannot reach storage url")?;
 
         if response.status() != StatusCode::from_u16(206).unwrap() {
             return Err("Invalid response status");
         }
 
-        let chunk_size = container.chunk_size;
+        self.response = Some(response);
 
-        Ok(Self { container, key, file_size, chunk_offset, count, current_chunk: 0, buffer: vec![0; chunk_size as usize], buffer_cursor: chunk_size as usize, response })
+        Ok(())
     }
 
-    fn download_chunk(&mut self) -> Result<(), &str> {
+    fn read_response_chunk(&mut self) -> Result<Vec<u8>, &'static str> {
+        if self.response.is_none() {
+            self.open_response()?;
+        }
+
+        throttle(&self.rate_limiter, self.container.chunk_size as usize);
+
+        let response = self.response.as_mut().unwrap();
         let mut buffer = vec![0; self.container.chunk_size as usize];
 
         let mut read = 0;
 
         while read < buffer.len() {
-            let r = self.response.read(&mut buffer[read..]).expect("TODO: panic message");
+            let r = response.read(&mut buffer[read..]).map_err(|_| "Cannot read chunk")?;
+
+            if r == 0 {
+                return Err("Unexpected end of container");
+            }
+
             read += r;
         }
 
+        Ok(buffer)
+    }
+
+    fn download_chunk(&mut self) -> Result<(), &str> {
+        let chunk = self.chunk_offset + self.current_chunk;
+
         // println!("Download: Read {} bytes (chunk {})", read, self.current_chunk + self.chunk_offset);
 
-        let chunk_start = self.container.bytes_range[0] + (self.current_chunk + self.chunk_offset) * (self.container.chunk_size - METADATA_SIZE as u64);
+        let content_size = chunk_content_size(&self.container, self.file_size, chunk);
+
+        if let Some(cache) = self.cache.clone() {
+            if let Some(mut buffer) = cache.get(&self.container, chunk) {
+                // the response would be behind, it is opened again at the next miss
+                self.response = None;
+
+                match self.decrypt_and_verify_chunk(&mut buffer, content_size) {
+                    Ok(data) => {
+                        self.buffer = data;
+                        self.events.chunk_decrypted(chunk, 0, content_size as u64);
+                        return Ok(());
+                    }
+                    Err(_) => cache.remove(&self.container, chunk),
+                }
+            }
+        }
 
-        let chunk_stop = min(self.file_size, chunk_start + self.container.chunk_size - (METADATA_SIZE as u64));
+        let mut buffer = self.read_response_chunk()?;
+        let encrypted = self.cache.as_ref().map(|_| buffer.clone());
 
-        return match self.decrypt_and_verify_chunk(&mut buffer, (chunk_stop - chunk_start) as usize) {
+        match self.decrypt_and_verify_chunk(&mut buffer, content_size) {
             Ok(data) => {
+                if let (Some(cache), Some(encrypted)) = (self.cache.as_ref(), encrypted) {
+                    // the cache is best effort
+                    let _ = cache.insert(&self.container, chunk, &encrypted);
+                }
+
                 self.buffer = data;
+                self.events.chunk_decrypted(chunk, self.container.chunk_size, content_size as u64);
                 Ok(())
             }
             Err(err) => {
                 eprintln!("Error: {}", err);
                 Err("Cannot decrypt chunk")
             }
-        };
+        }
     }
 
-    fn decrypt_and_verify_chunk(&self, chunk: &mut Vec<u8>, content_size: usize) -> Result<Vec<u8>, &str> {
+    fn decrypt_and_verify_chunk(&self, chunk: &mut [u8], content_size: usize) -> Result<Vec<u8>, &str> {
         //println!("Decrypting and verifying chunk of size {} (real {})", content_size, chunk.len());
 
-        let chunk_size = self.container.chunk_size as usize;
-
-        if chunk.len() != chunk_size {
+        if chunk.len() != self.container.chunk_size as usize {
             return Err("Chunk size mismatch");
         }
 
-        let salt = chunk[(chunk_size - 48)..(chunk_size - 32)].to_vec();
-        let hash = chunk[(chunk_size - 32)..].to_vec();
-
-        //println!("Read salt: {:X?}", salt);
-        //println!("Read hash: {:X?}", hash);
-
-        let cipher = Aes256Cbc::new_from_slices(
-            &self.key.clone(),
-            &salt.clone(),
-        ).unwrap();
-
-        if let Err(err) = cipher.decrypt(&mut chunk[0..(chunk_size - 48)]) {
-            eprintln!("Error: {err}");
-            return Err("Cannot decrypt chunk");
-        }
-
-        // compute hash
-        let data = chunk[0..content_size].to_vec();
-
-        let mut hasher = Sha256::new();
-        hasher.update(&chunk[0..((self.container.chunk_size as usize) - (METADATA_SIZE))]);
-        let data_hash = hasher.finalize();
-
-        if hash != data_hash.to_vec() {
-            return Err("Hash mismatch");
-        }
+        decrypt_chunk(&self.key, chunk)?;
 
-        Ok(data.to_vec())
+        Ok(chunk[0..content_size].to_vec())
     }
 }
 
@@ -272,7 +447,8 @@ impl Read for ByteStream {
                 if self.current_chunk >= self.count as u64 {
                     return Ok(read);
                 } else {
-                    self.download_chunk().expect("TODO: panic message");
+                    self.download_chunk()
+                        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
                     self.current_chunk += 1;
                     self.buffer_cursor = 0;
                 }
@@ -284,37 +460,177 @@ impl Read for ByteStream {
 }
 
 impl Downloader for FileDownloader {
-    fn download_file(&self, file_path: String) {
-        let mut f = File::create(file_path).unwrap();
-
-        let signal = &mut self.signal.get_report_signal(0);
+    fn download_file(&self, file_path: String) -> std::io::Result<()> {
+        let mut f = File::create(&file_path)?;
 
         let mut containers = self.waterfall.clone().containers.clone();
         containers.sort_by(|a,b| a.bytes_range[0].cmp(&b.bytes_range[0]));
 
-        for ctn in containers.iter() {
-            let container = self.get_container_downloader(ctn.clone());
-            let mut stream = container.get_byte_stream(0, ctn.chunk_count as usize).unwrap();
+        let file_hash = if self.threads <= 1 {
+            let mut hasher = Sha256::new();
+
+            for ctn in containers.iter() {
+                self.control.wait_while_paused();
+                self.control.check_cancelled()?;
 
-            let mut buf = [0u8; 65536 - 64];
-            let mut to_write = (ctn.bytes_range[1] - ctn.bytes_range[0]) as usize;
+                self.download_container(ctn, 0, &mut f, Some(&mut hasher))?;
+            }
 
-            //println!("to_write: {}", to_write);
+            hasher.finalize()
+        } else {
+            f.set_len(self.waterfall.size)?;
+            drop(f);
 
-            while to_write > 0 {
-                let read = stream.read(&mut buf).unwrap();
+            self.download_containers(&file_path, containers)?;
 
-                let c = to_write.min(read);
-                f.write_all(&mut buf[..c]).expect("TODO: panic message");
-                // println!("to_write: {}", to_write);
+            // the containers are written out of order, hash the file once complete
+            let mut hasher = Sha256::new();
+            std::io::copy(&mut File::open(&file_path)?, &mut hasher)?;
+            hasher.finalize()
+        };
 
-                if let Some(s) = signal {
-                    s.report_data(c as u64);
+        if let Some(expected_hash) = self.waterfall.file_hash {
+            if expected_hash != <[u8; 32]>::from(file_hash) {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "File hash mismatch"));
+            }
+        }
+
+        self.events.clone().report_data(TransferEvent::TransferDone);
+
+        Ok(())
+    }
+}
+
+impl FileDownloader {
+    /// Download the containers with `threads` workers, each writing
+    /// its containers at their offset in the file
+    fn download_containers(&self, file_path: &str, containers: Vec<Container>) -> std::io::Result<()> {
+        let queue = Mutex::new(VecDeque::from(containers));
+        let failed = AtomicBool::new(false);
+
+        scope(|scope| {
+            let workers: Vec<_> = (0..self.threads)
+                .map(|worker| {
+                    let downloader = self.clone();
+                    let queue = &queue;
+                    let failed = &failed;
+
+                    scope.spawn(move || -> std::io::Result<()> {
+                        let mut f = OpenOptions::new().write(true).open(file_path)?;
+
+                        loop {
+                            downloader.control.wait_while_paused();
+                            downloader.control.check_cancelled()?;
+
+                            if failed.load(Ordering::SeqCst) {
+                                return Ok(());
+                            }
+
+                            let Some(ctn) = queue.lock().unwrap().pop_front() else {
+                                return Ok(());
+                            };
+
+                            f.seek(SeekFrom::Start(ctn.bytes_range[0]))?;
+
+                            if let Err(err) = downloader.download_container(&ctn, worker, &mut f, None) {
+                                failed.store(true, Ordering::SeqCst);
+                                return Err(err);
+                            }
+                        }
+                    })
+                })
+                .collect();
+
+            let results: Vec<std::io::Result<()>> = workers.into_iter()
+                .map(|worker| worker.join().unwrap())
+                .collect();
+
+            results.into_iter().find(|result| result.is_err()).unwrap_or(Ok(()))
+        })
+    }
+
+    /// Write the content of a container at the current position of `f`
+    fn download_container(&self, ctn: &Container, worker: usize, f: &mut File, mut hasher: Option<&mut Sha256>) -> std::io::Result<()> {
+        let mut container = self.get_container_downloader(ctn.clone());
+        container.worker = worker;
+
+        let mut events = container.container_events();
+        let signal = &mut self.signal.get_report_signal(ctn.bytes_range[0]);
+
+        events.started();
+
+        let mut stream: Option<ByteStream> = None;
+        let mut chunk = 0u64;
+        let mut attempt = 0;
+
+        let mut buf = vec![0u8; ctn.chunk_size as usize - METADATA_SIZE];
+        let mut to_write = (ctn.bytes_range[1] - ctn.bytes_range[0]) as usize;
+
+        //println!("to_write: {}", to_write);
+
+        while to_write > 0 {
+            self.control.check_cancelled()?;
+
+            // a buffer of the chunk real size reads exactly one chunk
+            let read = match Self::read_chunk(&container, &mut stream, chunk, &mut buf) {
+                // retries are counted per failure, not for the whole container
+                Ok(read) => {
+                    attempt = 0;
+                    read
+                }
+                Err(err) if attempt < DOWNLOAD_RETRIES => {
+                    attempt += 1;
+                    events.retry(attempt, err.to_string());
+
+                    // start again from the chunk that failed
+                    stream = None;
+                    sleep(Duration::from_secs(attempt as u64));
+                    continue;
+                }
+                Err(err) => {
+                    events.failed(err.to_string());
+                    return Err(err);
                 }
+            };
 
-                to_write -= c;
+            chunk += 1;
+
+            let c = to_write.min(read);
+            f.write_all(&buf[..c])?;
+
+            if let Some(hasher) = hasher.as_mut() {
+                hasher.update(&buf[..c]);
+            }
+            // println!("to_write: {}", to_write);
+
+            if let Some(s) = signal {
+                s.report_data(c as u64);
             }
+
+            to_write -= c;
+        }
+
+        events.committed(ctn.storage_url.clone());
+
+        Ok(())
+    }
+
+    /// Read the next chunk of a container, opening the stream at `chunk` if needed
+    fn read_chunk(container: &ContainerDownloader, stream: &mut Option<ByteStream>, chunk: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        if stream.is_none() {
+            let count = container.container.chunk_count - chunk;
+
+            *stream = Some(container.get_byte_stream(chunk, count as usize)
+                .map_err(|err| std::io::Error::other(err.to_string()))?);
+        }
+
+        let read = stream.as_mut().unwrap().read(buf)?;
+
+        if read == 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Container ended early"));
         }
+
+        Ok(read)
     }
 }
 
@@ -324,193 +640,263 @@ impl ByteRangeDownloader for FileDownloader {
     }
 }
 
+// Reads `[range[0], range[1])` of the file, only downloading
+// the containers and the chunks overlapping the range
 pub struct ByteRangeStreamDownloader {
     range: [u64; 2],
     file_downloader: FileDownloader,
     position: u64,
     current_container: Option<Container>,
-    buffer: Vec<u8>,
-    buffer_cursor: Option<usize>,
-
     current_bytestream: Option<ByteStream>,
     sorted_containers: Vec<Container>,
+    attempt: u32,
 }
 
 impl ByteRangeStreamDownloader {
+    /// The range is clamped to the size of the file
     pub fn new(range: [u64; 2], file_downloader: FileDownloader) -> Self {
         let mut sorted_containers = file_downloader.waterfall.containers.clone();
-        sorted_containers.sort_by(|a, b| a.bytes_range[0].cmp(&b.bytes_range[0]));
+        sorted_containers.sort_by_key(|container| container.bytes_range[0]);
+
+        let end = min(range[1], file_downloader.waterfall.size);
+        let start = min(range[0], end);
 
         ByteRangeStreamDownloader {
-            range,
+            range: [start, end],
             file_downloader,
 
-            position: range[0],
+            position: start,
             current_container: None,
-            buffer: Vec::new(),
-            buffer_cursor: None,
             current_bytestream: None,
 
             sorted_containers,
+            attempt: 0,
         }
     }
 
     fn find_container(&self, start: u64) -> Option<Container> {
-        for container in self.sorted_containers.clone() {
-            if start >= container.bytes_range[0] && start < container.bytes_range[1] {
-                return Some(container);
-            }
-        }
-        None
+        self.sorted_containers.iter()
+            .find(|container| start >= container.bytes_range[0] && start < container.bytes_range[1])
+            .cloned()
     }
 
     fn get_remaining(&self) -> u64 {
         self.range[1] - self.position
     }
 
-    fn read_all_into_buff(&mut self) -> usize {
-        return match self.current_bytestream {
-            Some(ref mut stream) => {
-                let mut read = 0;
+    /// Open a stream on the container holding the current position,
+    /// from the chunk holding it to the chunk holding the end of the range
+    fn start_container_download(&mut self) -> std::io::Result<()> {
+        let container = self.find_container(self.position)
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("No container holds byte {}", self.position)))?;
 
-                while read < self.buffer.len() {
-                    let r = stream.read(&mut self.buffer[read..]).expect("TODO: panic message");
-                    read += r;
-                }
+        let real_size = container.chunk_size - METADATA_SIZE as u64;
+        let offset = self.position - container.bytes_range[0];
+        let end = min(self.range[1], container.bytes_range[1]) - container.bytes_range[0];
 
-                read
-            }
-            None => 0
-        };
-    }
+        let chunk_start = offset / real_size;
+        let chunk_end = min(container.chunk_count, end.div_ceil(real_size));
 
-    fn is_container_out_of_bound(&self) -> bool {
-        return match self.current_container {
-            Some(ref container) => {
-                self.position >= container.bytes_range[1]
-            }
-            None => true
-        };
-    }
+        let container_downloader = self.file_downloader.get_container_downloader(container.clone());
 
-    fn is_position_out_of_bound(&self) -> bool {
-        return self.position >= self.range[1];
-    }
+        let mut stream = container_downloader.get_byte_stream(chunk_start, (chunk_end - chunk_start) as usize)
+            .map_err(|err| std::io::Error::other(err.to_string()))?;
 
-    fn update_container(&mut self) {
-        self.current_container = self.find_container(self.position);
-        //println!("Update container: {:?}", self.current_container);
-    }
+        // skip the beginning of the first chunk
+        let skip = offset - chunk_start * real_size;
+        let skipped = std::io::copy(&mut (&mut stream).take(skip), &mut std::io::sink())?;
 
-    fn start_container_download(&mut self) {
-        if let Some(ref container) = self.current_container {
-            let start = self.position - container.bytes_range[0];
-            let chunk_size = self.get_chunk_real_size() as u64;
-            // make start a multiple of chunk_size
-            let chunk_start = start / chunk_size;
-            let chunk_end = min(container.chunk_count, ((min(self.range[1], container.bytes_range[1]) - container.bytes_range[0]) / chunk_size) + 1);
+        if skipped != skip {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Container ended early"));
+        }
 
-            let container_downloader = self.file_downloader.get_container_downloader(container.clone());
+        self.current_container = Some(container);
+        self.current_bytestream = Some(stream);
 
-            //println!("Starting container downloader start: {} || start : {} | end : {}", start, chunk_start, chunk_end);
+        Ok(())
+    }
 
-            self.current_bytestream = container_downloader.get_byte_stream(chunk_start, (chunk_end - chunk_start) as usize).ok();
+    fn read_current_container(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.current_bytestream.is_none() {
+            self.start_container_download()?;
         }
-    }
 
-    fn get_buffer_cursor(&self) -> usize {
-        return match self.buffer_cursor {
-            Some(cursor) => cursor,
-            None => 0
-        };
-    }
+        let read = self.current_bytestream.as_mut().unwrap().read(buf)?;
 
-    fn set_buffer_cursor(&mut self, cursor: usize) {
-        self.buffer_cursor = Some(cursor);
-    }
+        if read == 0 {
+            let container_end = self.current_container.as_ref().map(|container| container.bytes_range[1]).unwrap_or_default();
 
-    fn get_chunk_real_size(&self) -> usize {
-        return match self.current_container {
-            Some(ref container) => container.chunk_size as usize - METADATA_SIZE,
-            None => 0
-        };
-    }
+            if self.position < container_end {
+                return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Container ended early"));
+            }
 
-    fn get_current_chunk_size(&self, offset: usize) -> usize {
-        let real_size = self.get_chunk_real_size();
-        let remaining = self.get_remaining() as usize;
+            // move on to the next container
+            self.current_container = None;
+            self.current_bytestream = None;
+        }
 
-        return min(real_size, remaining + offset);
+        Ok(read)
     }
+}
+
+impl Read for ByteRangeStreamDownloader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut read = 0;
 
-    fn get_skip_offset(&self) -> usize {
-        match self.current_container {
-            Some(ref container) => {
-                let chunk_size = self.get_chunk_real_size() as u64;
+        while read < buf.len() && self.position < self.range[1] {
+            self.file_downloader.control.check_cancelled()?;
 
-                // we are in the first chunk
-                if self.position == self.range[0] {
-                    let start = self.position - container.bytes_range[0];
+            let size = min(buf.len() - read, self.get_remaining() as usize);
 
-                    let chunk_start = start / chunk_size;
+            match self.read_current_container(&mut buf[read..(read + size)]) {
+                Ok(r) => {
+                    read += r;
+                    self.position += r as u64;
+                    self.attempt = 0;
+                }
+                Err(_) if self.attempt < DOWNLOAD_RETRIES => {
+                    // open the stream again at the current position
+                    self.attempt += 1;
+                    self.current_container = None;
+                    self.current_bytestream = None;
 
+                    if read > 0 {
+                        return Ok(read);
+                    }
 
-                    return (self.position - (chunk_start * chunk_size)) as usize;
+                    sleep(Duration::from_secs(self.attempt as u64));
                 }
+                Err(err) => return Err(err),
             }
-            None => {}
         }
 
-        return 0;
+        Ok(read)
     }
 }
+/// Serve `content` encrypted like a single container uploaded with an empty
+/// password, a null salt and one KDF iteration, and return its url.
+/// Each response stops after `chunks_per_response` chunks, like a dropped connection.
+#[cfg(test)]
+pub(crate) fn serve_container(content: &[u8], chunk_size: usize, chunks_per_response: usize) -> String {
+    use std::net::TcpListener;
+    use crate::chunk::encrypt_chunk;
+
+    let real_size = chunk_size - METADATA_SIZE;
+    let key = derive_key("", &[0u8; 16], 1);
+
+    let mut bytes = Vec::new();
+
+    // one more chunk when the content fills the last one, like the uploader
+    for index in 0..content.len() / real_size + 1 {
+        let mut chunk = vec![0u8; chunk_size];
+        let part = &content[index * real_size..content.len().min((index + 1) * real_size)];
+        chunk[..part.len()].copy_from_slice(part);
+
+        encrypt_chunk(&key, &[7u8; 16], &mut chunk);
+        bytes.extend_from_slice(&chunk);
+    }
 
-impl Read for ByteRangeStreamDownloader {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let mut read = 0;
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/data.enc", listener.local_addr().unwrap());
 
-        while read < buf.len() {
-            let buffer_cursor = self.get_buffer_cursor();
-            //println!("Read loop {} over {} (position {})", buffer_cursor, self.buffer.len(), self.position);
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else { continue };
 
-            if buffer_cursor < self.buffer.len() {
-                let remain = min(buf.len() - read, self.buffer.len() - buffer_cursor);
-                buf[read..(read + remain)].clone_from_slice(&self.buffer[buffer_cursor..(buffer_cursor + remain)]);
-                read += remain;
-                self.position += remain as u64;
-                self.set_buffer_cursor(buffer_cursor + remain);
-            }
+            let mut request = Vec::new();
+            let mut buffer = [0u8; 1024];
 
-            if self.is_position_out_of_bound() {
-                break;
+            while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                match stream.read(&mut buffer) {
+                    Ok(0) | Err(_) => break,
+                    Ok(read) => request.extend_from_slice(&buffer[..read]),
+                }
             }
 
-            if self.is_container_out_of_bound() {
-                //println!("Container out of bound");
-                self.update_container();
-                self.start_container_download();
-            }
+            // range: bytes=start-end, end included
+            let request = String::from_utf8_lossy(&request).to_lowercase();
+            let range = request.lines()
+                .find_map(|line| line.strip_prefix("range: bytes="))
+                .and_then(|range| range.split_once('-'))
+                .and_then(|(start, end)| Some((start.parse::<usize>().ok()?, end.parse::<usize>().ok()?)));
 
-            if buffer_cursor >= self.buffer.len() {
-                // load next chunk (shrink) into memory
-                let offset = self.get_skip_offset();
-                let size = self.get_current_chunk_size(offset);
+            let Some((start, end)) = range else { continue };
+            let end = end.min(bytes.len() - 1);
+            let sent = (end + 1 - start).min(chunks_per_response.saturating_mul(chunk_size));
 
-                // if current buffer is not big enough, resize it
-                if size != self.buffer.len() {
-                    self.buffer = vec![0; size];
-                }
+            let _ = write!(stream, "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", end + 1 - start);
+            let _ = stream.write_all(&bytes[start..start + sent]);
+        }
+    });
 
-                self.read_all_into_buff();
+    url
+}
 
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use crate::common::{Container, Waterfall};
+    use crate::control::{Cancelled, TransferControl};
+    use crate::downloader::{serve_container, Downloader, FileDownloader, WaterfallDownloader, DOWNLOAD_RETRIES};
+
+    fn test_waterfall() -> Waterfall {
+        Waterfall {
+            filename: "file.bin".to_string(),
+            password: String::new(),
+            size: 100,
+            file_hash: None,
+            manifest_level: 0,
+            containers: vec![Container::test_fixture(1, [0, 100])],
+        }
+    }
 
-                //println!("Current size: {}, offset : {}", size, offset);
+    #[test]
+    fn test_cancelled_range_reader() {
+        let control = TransferControl::new();
 
-                self.buffer = self.buffer[offset..].to_vec();
-                self.buffer_cursor = Some(0);
-            }
-        }
+        let mut downloader = FileDownloader::from_waterfall(test_waterfall());
+        downloader.with_control(&control);
 
-        Ok(read)
+        control.cancel();
+
+        // read_to_end retries interrupted reads, a cancelled reader must fail instead
+        let err = downloader.get_range(0, 100).read_to_end(&mut Vec::new()).unwrap_err();
+        assert!(Cancelled::is_cancelled(&err));
+    }
+
+    #[test]
+    fn test_oversized_manifest() {
+        let mut waterfall = test_waterfall();
+        waterfall.size = u64::MAX;
+        waterfall.containers[0].bytes_range = [0, u64::MAX];
+        waterfall.manifest_level = 1;
+
+        // rejected before anything is allocated or downloaded
+        let err = FileDownloader::from_waterfall(waterfall).resolve_root_pointer().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_retries_per_failure() {
+        let content: Vec<u8> = (0..5000u32).map(|i| i as u8).collect();
+
+        // every response drops after two chunks, and a read fetches the chunk
+        // after the one it returns: reads fail once per chunk from the third on
+        let mut waterfall = test_waterfall();
+        waterfall.size = content.len() as u64;
+        waterfall.containers[0].storage_url = serve_container(&content, 1024, 2);
+        waterfall.containers[0].chunk_size = 1024;
+        waterfall.containers[0].chunk_count = 6;
+        waterfall.containers[0].bytes_range = [0, content.len() as u64];
+        assert!(waterfall.containers[0].chunk_count > DOWNLOAD_RETRIES as u64 + 1);
+
+        let path = std::env::temp_dir().join(format!("discord-us-retries-{}", rand::random::<u64>()));
+
+        let result = FileDownloader::from_waterfall(waterfall).download_file(path.to_string_lossy().to_string());
+        let downloaded = std::fs::read(&path);
+        let _ = std::fs::remove_file(&path);
+
+        result.unwrap();
+        assert_eq!(downloaded.unwrap(), content);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,189 @@
+use std::ffi::OsStr;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+use fuser::{FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, ReplyOpen, Request};
+use crate::vfs::{FileAttributes, WaterfallFs, ROOT_INODE};
+
+// FUSE mount
+// Read-only mount of a WaterfallFs on Linux. The kernel requests are
+// answered by the WaterfallFs: lookups and attributes from its file list,
+// reads from its block cache and ranged downloaders.
+
+/// The directory does not change while mounted, attributes can be kept by the kernel
+const TTL: Duration = Duration::from_secs(60);
+
+const BLOCK_SIZE: u32 = 4096;
+
+/// Mount the filesystem read-only and serve it until it is unmounted
+/// (`fusermount -u <mountpoint>`)
+pub fn mount<P: AsRef<Path>>(fs: WaterfallFs, mountpoint: P) -> std::io::Result<()> {
+    let options = [
+        MountOption::RO,
+        MountOption::FSName("discord-us".to_string()),
+        MountOption::Subtype("waterfall".to_string()),
+    ];
+
+    fuser::mount2(WaterfallMount { fs }, mountpoint, &options)
+}
+
+struct WaterfallMount {
+    fs: WaterfallFs,
+}
+
+impl WaterfallMount {
+    /// `.`, `..` and the files, with their inode and kind
+    fn entries(&self) -> Vec<(u64, FileType, String)> {
+        let mut entries = vec![
+            (ROOT_INODE, FileType::Directory, ".".to_string()),
+            (ROOT_INODE, FileType::Directory, "..".to_string()),
+        ];
+
+        entries.extend(self.fs.list().into_iter()
+            .map(|file| (file.inode, FileType::RegularFile, file.name.clone())));
+
+        entries
+    }
+}
+
+fn directory_attr(req: &Request<'_>) -> FileAttr {
+    FileAttr {
+        ino: ROOT_INODE,
+        size: 0,
+        blocks: 0,
+        atime: UNIX_EPOCH,
+        mtime: UNIX_EPOCH,
+        ctime: UNIX_EPOCH,
+        crtime: UNIX_EPOCH,
+        kind: FileType::Directory,
+        perm: 0o555,
+        nlink: 2,
+        uid: req.uid(),
+        gid: req.gid(),
+        rdev: 0,
+        blksize: BLOCK_SIZE,
+        flags: 0,
+    }
+}
+
+fn file_attr(file: &FileAttributes, uid: u32, gid: u32) -> FileAttr {
+    let modified = UNIX_EPOCH + Duration::from_secs(file.modified);
+
+    FileAttr {
+        ino: file.inode,
+        size: file.size,
+        blocks: file.size.div_ceil(512),
+        atime: modified,
+        mtime: modified,
+        ctime: modified,
+        crtime: modified,
+        kind: FileType::RegularFile,
+        perm: 0o444,
+        nlink: 1,
+        uid,
+        gid,
+        rdev: 0,
+        blksize: BLOCK_SIZE,
+        flags: 0,
+    }
+}
+
+impl Filesystem for WaterfallMount {
+    fn lookup(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        if parent != ROOT_INODE {
+            return reply.error(libc::ENOENT);
+        }
+
+        let file = name.to_str()
+            .and_then(|name| self.fs.lookup(name))
+            .and_then(|inode| self.fs.get_attributes(inode));
+
+        match file {
+            Some(file) => reply.entry(&TTL, &file_attr(file, req.uid(), req.gid()), 0),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, req: &Request<'_>, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        if ino == ROOT_INODE {
+            return reply.attr(&TTL, &directory_attr(req));
+        }
+
+        match self.fs.get_attributes(ino) {
+            Some(file) => reply.attr(&TTL, &file_attr(file, req.uid(), req.gid())),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        if self.fs.get_attributes(ino).is_none() {
+            return reply.error(libc::ENOENT);
+        }
+
+        if flags & libc::O_ACCMODE != libc::O_RDONLY {
+            return reply.error(libc::EROFS);
+        }
+
+        reply.opened(0, 0);
+    }
+
+    fn read(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64, size: u32, _flags: i32, _lock_owner: Option<u64>, reply: ReplyData) {
+        let Ok(offset) = u64::try_from(offset) else {
+            return reply.error(libc::EINVAL);
+        };
+
+        match self.fs.read(ino, offset, size as usize) {
+            Ok(data) => reply.data(&data),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => reply.error(libc::ENOENT),
+            Err(err) => {
+                eprintln!("Cannot read inode {} at {}: {}", ino, offset, err);
+                reply.error(libc::EIO)
+            }
+        }
+    }
+
+    fn readdir(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        if ino != ROOT_INODE {
+            return reply.error(libc::ENOTDIR);
+        }
+
+        // the offset of an entry is the one of the next entry
+        for (index, (inode, kind, name)) in self.entries().into_iter().enumerate().skip(offset.max(0) as usize) {
+            if reply.add(inode, index as i64 + 1, kind, name) {
+                break;
+            }
+        }
+
+        reply.ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fuser::FileType;
+    use crate::common::Waterfall;
+    use crate::fuse::{file_attr, WaterfallMount};
+    use crate::vfs::{FileAttributes, WaterfallFs, ROOT_INODE};
+
+    #[test]
+    fn test_entries() {
+        let mut fs = WaterfallFs::new();
+        let waterfall = Waterfall {
+            filename: "movie.mkv".to_string(),
+            password: String::new(),
+            size: 0,
+            file_hash: None,
+            manifest_level: 0,
+            containers: Vec::new(),
+        };
+        let inode = fs.add(waterfall, None, 5).unwrap();
+
+        let entries = WaterfallMount { fs }.entries();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0], (ROOT_INODE, FileType::Directory, ".".to_string()));
+        assert_eq!(entries[2], (inode, FileType::RegularFile, "movie.mkv".to_string()));
+
+        let attr = file_attr(&FileAttributes { inode, name: "movie.mkv".to_string(), size: 1000, modified: 5 }, 1000, 100);
+        assert_eq!((attr.ino, attr.size, attr.blocks, attr.perm), (inode, 1000, 2, 0o444));
+        assert_eq!(attr.mtime.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs(), 5);
+    }
+}
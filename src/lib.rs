@@ -1,5 +1,19 @@
 pub mod uploader;
 pub mod downloader;
-mod http_client;
+pub mod http_client;
 pub mod common;
-pub mod signal;
\ No newline at end of file
+pub mod signal;
+pub mod control;
+pub mod throttle;
+pub mod share;
+pub mod catalog;
+pub mod vfs;
+#[cfg(feature = "fuse")]
+pub mod fuse;
+pub mod cache;
+pub mod credentials;
+mod chunk;
+#[cfg(feature = "async")]
+pub mod async_downloader;
+#[cfg(feature = "async")]
+pub mod async_uploader;
\ No newline at end of file
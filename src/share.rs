@@ -0,0 +1,124 @@
+use std::io::{Read, Write};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use crate::common::{Waterfall, WaterfallFormat};
+
+// Share strings
+// dus://<payload>#<key>
+// payload: the binary waterfall without its password, deflated and encoded in base64url
+// key: the password encoded in base64url, optional
+pub const SHARE_SCHEME: &str = "dus://";
+
+/// Limit of a decompressed payload or of a manifest, both are untrusted input
+pub(crate) const MAX_PAYLOAD_SIZE: u64 = 64 * 1024 * 1024;
+
+impl Waterfall {
+    /// The password is only kept in the fragment, and left out unless `with_password`
+    pub fn to_share_string(&self, with_password: bool) -> std::io::Result<String> {
+        let mut waterfall = self.clone();
+        let password = std::mem::take(&mut waterfall.password);
+
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(&waterfall.to_bytes(WaterfallFormat::Binary)?)?;
+        let payload = encoder.finish()?;
+
+        let mut share = format!("{}{}", SHARE_SCHEME, URL_SAFE_NO_PAD.encode(payload));
+
+        if with_password && !password.is_empty() {
+            share.push('#');
+            share.push_str(&URL_SAFE_NO_PAD.encode(password));
+        }
+
+        Ok(share)
+    }
+
+    pub fn from_share_string(share: &str) -> std::io::Result<Waterfall> {
+        let share = share.trim()
+            .strip_prefix(SHARE_SCHEME)
+            .ok_or_else(|| invalid_share(format!("A share string starts with {}", SHARE_SCHEME)))?;
+
+        let (payload, key) = match share.split_once('#') {
+            Some((payload, key)) => (payload, Some(key)),
+            None => (share, None),
+        };
+
+        let payload = URL_SAFE_NO_PAD.decode(payload)
+            .map_err(|err| invalid_share(format!("Invalid payload: {}", err)))?;
+
+        let mut bytes = Vec::new();
+        DeflateDecoder::new(payload.as_slice())
+            .take(MAX_PAYLOAD_SIZE + 1)
+            .read_to_end(&mut bytes)
+            .map_err(|err| invalid_share(format!("Invalid payload: {}", err)))?;
+
+        if bytes.len() as u64 > MAX_PAYLOAD_SIZE {
+            return Err(invalid_share("Payload is too large".to_string()));
+        }
+
+        let mut waterfall = Waterfall::from_bytes(&bytes)?;
+
+        if let Some(key) = key.filter(|key| !key.is_empty()) {
+            let password = URL_SAFE_NO_PAD.decode(key)
+                .ok()
+                .and_then(|password| String::from_utf8(password).ok())
+                .ok_or_else(|| invalid_share("Invalid key".to_string()))?;
+
+            waterfall.password = password;
+        }
+
+        Ok(waterfall)
+    }
+}
+
+pub fn is_share_string(value: &str) -> bool {
+    value.trim_start().starts_with(SHARE_SCHEME)
+}
+
+fn invalid_share(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Invalid share string: {}", message))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{Container, Waterfall};
+    use crate::share::is_share_string;
+
+    fn waterfall() -> Waterfall {
+        Waterfall {
+            filename: "file.bin".to_string(),
+            password: "s3cr#t p@ss".to_string(),
+            size: 10,
+            file_hash: Some([1u8; 32]),
+            manifest_level: 0,
+            containers: vec![Container {
+                storage_url: "https://cdn.discordapp.com/attachments/1/2/data.enc?ex=65a1b2c3".to_string(),
+                salt: [2u8; 16],
+                ..Container::test_fixture(1, [0, 10])
+            }],
+        }
+    }
+
+    #[test]
+    fn test_share_string() {
+        let w = waterfall();
+
+        let with_key = w.to_share_string(true).unwrap();
+        assert!(is_share_string(&with_key));
+        assert!(!with_key.contains(&w.password));
+
+        let decoded = Waterfall::from_share_string(&with_key).unwrap();
+        assert_eq!(serde_json::to_value(&decoded).unwrap(), serde_json::to_value(&w).unwrap());
+
+        let without_key = w.to_share_string(false).unwrap();
+        assert!(!without_key.contains('#'));
+        assert_eq!(with_key.split('#').next(), Some(without_key.as_str()));
+        assert_eq!(Waterfall::from_share_string(&without_key).unwrap().password, "");
+
+        assert!(Waterfall::from_share_string("https://example.com").is_err());
+        assert!(Waterfall::from_share_string("dus://not base64").is_err());
+        assert!(Waterfall::from_share_string(&format!("{}#%%", without_key)).is_err());
+    }
+}
@@ -2,10 +2,14 @@ use core::cmp::{Ord, PartialOrd, PartialEq};
 use std::cmp::Ordering;
 use std::fmt::Debug;
 use std::ops::{Add, AddAssign, Sub};
+use std::ops::Bound::Excluded;
 use std::sync::atomic::AtomicU64;
 use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::{Duration, Instant};
 
-use sorted_vec::SortedVec;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use dyn_clone::{DynClone, clone_trait_object};
 
 pub trait ReportSignal<D>: DynClone
@@ -24,7 +28,7 @@ pub trait Signal<D>
 // Linear progression
 // Only meant for 1 updater
 // multiple readers
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct LinearProgression {
     progression: Arc<AtomicU64>,
 }
@@ -86,17 +90,7 @@ impl<T: Integer> PartialEq for ProgressionRange<T> {
 
 impl<T: Integer> PartialOrd for ProgressionRange<T> {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        if self.range_start < other.range_start {
-            Some(Ordering::Less)
-        } else if self.range_start > other.range_start {
-            Some(Ordering::Greater)
-        } else if self.range_end < other.range_end {
-            Some(Ordering::Less)
-        } else if self.range_end > other.range_end {
-            Some(Ordering::Greater)
-        } else {
-            Some(Ordering::Equal)
-        }
+        Some(self.cmp(other))
     }
 }
 
@@ -118,76 +112,199 @@ impl<T: Integer> Ord for ProgressionRange<T> {
     }
 }
 
-#[derive(Clone, Debug)]
-pub struct PartProgression<T>
+// Range set
+// Disjoint ranges keyed by their start, ranges that overlap
+// or touch are merged when inserted.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RangeSet<T>
     where T: Integer {
-    progression: Arc<Mutex<SortedVec<ProgressionRange<T>>>>,
+    ranges: BTreeMap<T, T>,
+    total: T,
 }
 
-impl<T: Integer> ProgressionRange<T> {
-    pub fn of(range_start: T, range_end: T) -> Self {
-        Self {
-            range_start,
-            range_end,
-        }
+impl<T: Integer> RangeSet<T> {
+    pub fn new() -> Self {
+        Self { ranges: BTreeMap::new(), total: T::zero() }
     }
-}
 
-impl<T: Integer> PartProgression<T> {
-    pub fn new() -> Self {
-        Self {
-            progression: Arc::new(Mutex::new(SortedVec::new())),
+    pub fn insert(&mut self, range: ProgressionRange<T>) {
+        if range.range_start >= range.range_end {
+            return;
         }
+
+        let mut start = range.range_start;
+        let mut end = range.range_end;
+
+        // merge every range starting before the end of the new one and not ending before its start
+        while let Some((&range_start, &range_end)) = self.ranges.range(..=end).next_back() {
+            if range_end < start {
+                break;
+            }
+
+            self.ranges.remove(&range_start);
+            self.total = self.total - (range_end - range_start);
+
+            start = start.min(range_start);
+            end = end.max(range_end);
+        }
+
+        self.ranges.insert(start, end);
+        self.total += end - start;
     }
 
-    pub fn retrim_ranges(&mut self) {
-        // not optimal but i can't use retain_mut on SortedVec
-        // because it does not implement DerefMut.
-        // the solution is to clone into a Vec and then replace the
-        // current SortedVec with the new Vec
+    pub fn remove(&mut self, range: ProgressionRange<T>) {
+        if range.range_start >= range.range_end {
+            return;
+        }
 
-        let mut ranges = self.progression.lock().unwrap();
+        let overlapping: Vec<(T, T)> = self.ranges.range(..range.range_end)
+            .rev()
+            .take_while(|(_, &range_end)| range_end > range.range_start)
+            .map(|(&range_start, &range_end)| (range_start, range_end))
+            .collect();
 
-        let vec = ranges.to_vec();
+        for (range_start, range_end) in overlapping {
+            self.ranges.remove(&range_start);
+            self.total = self.total - (range_end - range_start);
 
-        let mut prev_i = 0;
+            if range_start < range.range_start {
+                self.ranges.insert(range_start, range.range_start);
+                self.total += range.range_start - range_start;
+            }
 
-        let mut new_vec: Vec<ProgressionRange<T>> = Vec::new();
+            if range_end > range.range_end {
+                self.ranges.insert(range.range_end, range_end);
+                self.total += range_end - range.range_end;
+            }
+        }
+    }
 
-        for i in 0..vec.len() {
-            let current = vec.get(i).unwrap().clone();
+    pub fn contains(&self, range: &ProgressionRange<T>) -> bool {
+        match self.ranges.range(..=range.range_start).next_back() {
+            Some((_, &range_end)) => range_end >= range.range_end,
+            None => range.range_start >= range.range_end,
+        }
+    }
 
-            if prev_i != i {
-                let prev = new_vec.get_mut(prev_i).unwrap();
+    /// Parts of `range` that are not in the set
+    pub fn missing(&self, range: ProgressionRange<T>) -> Vec<ProgressionRange<T>> {
+        let mut missing = Vec::new();
+        let mut cursor = range.range_start;
 
-                if current.range_start <= prev.range_end {
-                    //println!("Merged {:?}->{:?} + {:?}->{:?} = {:?}->{:?}", prev.range_start, prev.range_end, current.range_start, current.range_end, prev.range_start, current.range_end);
-                    prev.range_end = current.range_end;
-                    continue;
-                }
+        // the range containing the start, if any, then every range starting inside
+        let first = self.ranges.range(..=range.range_start).next_back();
+        let inside = self.ranges.range((Excluded(range.range_start), Excluded(range.range_end)));
+
+        for (&range_start, &range_end) in first.into_iter().chain(inside) {
+            if range_start > cursor {
+                missing.push(ProgressionRange::of(cursor, range_start.min(range.range_end)));
             }
 
-            prev_i = new_vec.len();
-            new_vec.push(current);
+            cursor = cursor.max(range_end);
+
+            if cursor >= range.range_end {
+                return missing;
+            }
         }
 
-        //println!("Did retrim on size {}", new_vec.len());
+        if cursor < range.range_end {
+            missing.push(ProgressionRange::of(cursor, range.range_end));
+        }
 
-        *ranges = SortedVec::from(new_vec);
+        missing
     }
 
-    pub fn get_total(&self) -> T {
-        let mut total: T = T::zero();
-        for range in self.get_data().iter() {
-            total += range.range_end - range.range_start;
+    /// Sum of the length of every range
+    pub fn total(&self) -> T {
+        self.total
+    }
+
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=ProgressionRange<T>> + '_ {
+        self.ranges.iter().map(|(&range_start, &range_end)| ProgressionRange::of(range_start, range_end))
+    }
+
+    pub fn to_vec(&self) -> Vec<ProgressionRange<T>> {
+        self.iter().collect()
+    }
+}
+
+impl<T: Integer> FromIterator<ProgressionRange<T>> for RangeSet<T> {
+    fn from_iter<I: IntoIterator<Item=ProgressionRange<T>>>(iter: I) -> Self {
+        let mut set = Self::new();
+
+        for range in iter {
+            set.insert(range);
         }
-        total
+
+        set
+    }
+}
+
+// Serialized as a list of [start, end] pairs
+impl<T: Integer + Serialize> Serialize for RangeSet<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.ranges.iter())
+    }
+}
+
+impl<'de, T: Integer + Deserialize<'de>> Deserialize<'de> for RangeSet<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let ranges: Vec<(T, T)> = Vec::deserialize(deserializer)?;
+
+        Ok(ranges.into_iter().map(|(range_start, range_end)| ProgressionRange::of(range_start, range_end)).collect())
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct PartProgression<T>
+    where T: Integer {
+    progression: Arc<Mutex<RangeSet<T>>>,
+}
+
+impl<T: Integer> ProgressionRange<T> {
+    pub fn of(range_start: T, range_end: T) -> Self {
+        Self {
+            range_start,
+            range_end,
+        }
+    }
+}
+
+impl<T: Integer> PartProgression<T> {
+    pub fn new() -> Self {
+        Self {
+            progression: Arc::new(Mutex::new(RangeSet::new())),
+        }
+    }
+
+    /// Ranges are merged when reported, kept for compatibility
+    pub fn retrim_ranges(&mut self) {}
+
+    pub fn get_total(&self) -> T {
+        self.progression.lock().unwrap().total()
+    }
+
+    /// Parts of `range` that were not reported yet
+    pub fn get_missing(&self, range: ProgressionRange<T>) -> Vec<ProgressionRange<T>> {
+        self.progression.lock().unwrap().missing(range)
+    }
+
+    pub fn get_ranges(&self) -> RangeSet<T> {
+        self.progression.lock().unwrap().clone()
     }
 }
 
 impl<T: Integer + Clone> ReportSignal<ProgressionRange<T>> for PartProgression<T> {
     fn report_data(&mut self, t: ProgressionRange<T>) {
-        self.progression.lock().unwrap().push(t);
+        self.progression.lock().unwrap().insert(t);
     }
 }
 
@@ -225,7 +342,8 @@ impl<T: Integer> LinearPartSignal<T> {
 // Test
 #[cfg(test)]
 mod tests {
-    use crate::signal::{PartProgression, Signal, ProgressionRange, ReportSignal, LinearPartSignal};
+    use std::time::Duration;
+    use crate::signal::{PartProgression, Signal, ProgressionRange, ReportSignal, ChannelSignal, MultiSignal, TransferEvent, TransferStats, RangeSet};
 
     #[test]
     fn test_ranges() {
@@ -257,6 +375,147 @@ mod tests {
         //
         // println!("{:?}", signal.get_data());
     }
+
+    #[test]
+    fn test_part_progression() {
+        let mut signal = PartProgression::new();
+
+        signal.report_data(ProgressionRange::of(0, 10));
+        signal.report_data(ProgressionRange::of(10, 20));
+
+        signal.report_data(ProgressionRange::of(100, 110));
+
+        signal.report_data(ProgressionRange::of(20, 30));
+        signal.report_data(ProgressionRange::of(30, 40));
+
+        signal.report_data(ProgressionRange::of(110, 120));
+
+        signal.report_data(ProgressionRange::of(40, 50));
+        signal.report_data(ProgressionRange::of(50, 60));
+
+        signal.report_data(ProgressionRange::of(120, 130));
+
+        // merged as they are reported
+        assert_eq!(signal.get_data(), vec![ProgressionRange::of(0, 60), ProgressionRange::of(100, 130)]);
+        assert_eq!(signal.get_total(), 90);
+    }
+
+    #[test]
+    fn test_range_set() {
+        let mut set = RangeSet::new();
+
+        set.insert(ProgressionRange::of(10u64, 20));
+        set.insert(ProgressionRange::of(30, 40));
+        set.insert(ProgressionRange::of(50, 60));
+
+        // contained, touching and overlapping several ranges
+        set.insert(ProgressionRange::of(12, 15));
+        set.insert(ProgressionRange::of(40, 45));
+        set.insert(ProgressionRange::of(15, 35));
+
+        assert_eq!(set.to_vec(), vec![ProgressionRange::of(10, 45), ProgressionRange::of(50, 60)]);
+        assert_eq!(set.total(), 45);
+
+        assert!(set.contains(&ProgressionRange::of(20, 45)));
+        assert!(!set.contains(&ProgressionRange::of(40, 55)));
+
+        assert_eq!(set.missing(ProgressionRange::of(0, 70)), vec![ProgressionRange::of(0, 10), ProgressionRange::of(45, 50), ProgressionRange::of(60, 70)]);
+        assert_eq!(set.missing(ProgressionRange::of(10, 48)), vec![ProgressionRange::of(45, 48)]);
+        assert!(set.missing(ProgressionRange::of(52, 58)).is_empty());
+
+        set.remove(ProgressionRange::of(40, 55));
+
+        assert_eq!(set.to_vec(), vec![ProgressionRange::of(10, 40), ProgressionRange::of(55, 60)]);
+        assert_eq!(set.total(), 35);
+
+        let json = serde_json::to_string(&set).unwrap();
+        assert_eq!(json, "[[10,40],[55,60]]");
+        assert_eq!(serde_json::from_str::<RangeSet<u64>>(&json).unwrap(), set);
+    }
+
+    #[test]
+    fn test_multi_signal() {
+        let (first, first_receiver) = ChannelSignal::new();
+        let (second, second_receiver) = ChannelSignal::new();
+
+        let mut signal = MultiSignal::new();
+        signal.add_signal(Box::new(first));
+        signal.add_signal(Box::new(second));
+
+        signal.clone().report_data(TransferEvent::ContainerStarted { worker: 0, index: 1 });
+        signal.report_data(TransferEvent::TransferDone);
+
+        for receiver in [first_receiver, second_receiver] {
+            let events: Vec<TransferEvent> = receiver.try_iter().collect();
+
+            assert_eq!(events, vec![TransferEvent::ContainerStarted { worker: 0, index: 1 }, TransferEvent::TransferDone]);
+        }
+    }
+
+    #[test]
+    fn test_transfer_stats() {
+        let stats = TransferStats::new(1000).with_window(Duration::from_secs(2));
+        let mut state = stats.state.lock().unwrap();
+
+        let start = state.start;
+        let at = |seconds: u64| start + Duration::from_secs(seconds);
+        let chunk = |worker, index, chunk| TransferEvent::ChunkEncrypted { worker, index, chunk, wire_bytes: 150, plain_bytes: 100 };
+
+        state.record(chunk(0, 1, 0), at(1));
+        state.record(chunk(1, 2, 0), at(1));
+        state.record(TransferEvent::Retry { worker: 1, index: 2, attempt: 1, error: String::new() }, at(2));
+
+        // the retried chunk goes on the wire again but is only counted once as progress
+        state.record(chunk(1, 2, 0), at(3));
+        state.record(chunk(1, 2, 1), at(4));
+
+        let snapshot = state.snapshot(at(4));
+
+        // nothing is kept for the containers done
+        state.record(TransferEvent::ContainerCommitted { worker: 0, index: 1, locator: String::new() }, at(4));
+        assert_eq!(state.counted_chunks.len(), 1);
+
+        assert_eq!(snapshot.wire_bytes, 600);
+        assert_eq!(snapshot.plain_bytes, 300);
+        assert_eq!(snapshot.retries, 1);
+
+        // only the chunks of the last 2 seconds count in the rate
+        assert_eq!(snapshot.rate, 50.0);
+        assert_eq!(snapshot.wire_rate, 150.0);
+        assert_eq!(snapshot.average_rate, 75.0);
+        assert_eq!(snapshot.eta, Some(Duration::from_secs(14)));
+
+        assert_eq!(snapshot.workers.len(), 2);
+        assert_eq!(snapshot.workers[0].rate, 0.0);
+        assert_eq!(snapshot.workers[1].rate, 50.0);
+        assert_eq!(snapshot.workers[1].retries, 1);
+        assert_eq!(snapshot.workers[1].wire_bytes, 450);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_stream_signal() {
+        use futures_util::StreamExt;
+        use crate::signal::{StreamSignal, STREAM_SIGNAL_CAPACITY};
+
+        let (signal, stream) = StreamSignal::new();
+
+        let dropped = signal.subscribe();
+        drop(dropped);
+        assert_eq!(signal.sender.receiver_count(), 1);
+
+        // a stream falling behind keeps the most recent reports
+        for i in 0..(STREAM_SIGNAL_CAPACITY + 10) {
+            signal.report(i);
+        }
+        drop(signal);
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let reports: Vec<usize> = runtime.block_on(stream.collect());
+
+        assert_eq!(reports.len(), STREAM_SIGNAL_CAPACITY);
+        assert_eq!(reports[0], 10);
+    }
 }
 
 // Callback signal
@@ -267,9 +526,9 @@ pub struct CallbackSignal<CB> {
 
 
 impl<CB> CallbackSignal<CB> {
-    fn trigger_callback<P> (self: &'_ mut Self, param: P)
+    fn trigger_callback<P> (&mut self, param: P)
         where
-            CB : Fn(P) -> (),
+            CB : Fn(P),
     {
         (self.callback)(param);
     }
@@ -277,9 +536,433 @@ impl<CB> CallbackSignal<CB> {
 
 impl<CB> ReportSignal<()> for CallbackSignal<CB>
     where
-        CB: Fn(()) -> () + Clone + 'static,
+        CB: Fn(()) + Clone + 'static,
 {
     fn report_data(&mut self, t: ()) {
         self.trigger_callback(t);
     }
-}
\ No newline at end of file
+}
+
+// Transfer events
+// Emitted by the uploader and the downloader so callers can follow
+// what each worker is doing, `worker` is the index of the thread
+// and `index` the index of the container.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TransferEvent {
+    ContainerStarted { worker: usize, index: u32 },
+    /// Discord allocated an upload url for the container, or its webhook message is being sent
+    SlotReserved { worker: usize, index: u32, size: u64 },
+    /// `wire_bytes` is the size of the chunk on Discord, `plain_bytes` the file bytes it holds
+    ChunkEncrypted { worker: usize, index: u32, chunk: u64, wire_bytes: u64, plain_bytes: u64 },
+    ChunkDecrypted { worker: usize, index: u32, chunk: u64, wire_bytes: u64, plain_bytes: u64 },
+    /// The container is stored on Discord (upload) or written to the output (download)
+    ContainerCommitted { worker: usize, index: u32, locator: String },
+    Retry { worker: usize, index: u32, attempt: u32, error: String },
+    ContainerFailed { worker: usize, index: u32, error: String },
+    TransferDone,
+}
+
+// Channel signal
+// Forwards every report to a receiver, reports are dropped
+// once the receiver is gone.
+pub struct ChannelSignal<D> {
+    sender: Sender<D>,
+}
+
+impl<D> Clone for ChannelSignal<D> {
+    fn clone(&self) -> Self {
+        Self { sender: self.sender.clone() }
+    }
+}
+
+impl<D> ChannelSignal<D> {
+    pub fn new() -> (Self, Receiver<D>) {
+        let (sender, receiver) = channel();
+
+        (Self { sender }, receiver)
+    }
+}
+
+impl<D> ReportSignal<D> for ChannelSignal<D> {
+    fn report_data(&mut self, t: D) {
+        let _ = self.sender.send(t);
+    }
+}
+
+// Stream signal
+// Forwards every report to async streams, through a bounded channel:
+// a stream falling behind loses its oldest reports instead of holding
+// every event of the transfer. Reports are dropped while no stream listens.
+#[cfg(feature = "async")]
+pub struct StreamSignal<D> {
+    sender: tokio::sync::broadcast::Sender<D>,
+}
+
+/// Reports kept for a stream that does not keep up
+#[cfg(feature = "async")]
+const STREAM_SIGNAL_CAPACITY: usize = 1024;
+
+#[cfg(feature = "async")]
+impl<D> Clone for StreamSignal<D> {
+    fn clone(&self) -> Self {
+        Self { sender: self.sender.clone() }
+    }
+}
+
+#[cfg(feature = "async")]
+impl<D: Clone + Send + 'static> Default for StreamSignal<D> {
+    fn default() -> Self {
+        let (sender, _) = tokio::sync::broadcast::channel(STREAM_SIGNAL_CAPACITY);
+
+        Self { sender }
+    }
+}
+
+#[cfg(feature = "async")]
+impl<D: Clone + Send + 'static> StreamSignal<D> {
+    pub fn new() -> (Self, SignalStream<D>) {
+        let signal = Self::default();
+        let stream = signal.subscribe();
+
+        (signal, stream)
+    }
+
+    /// Another stream of the reports made from now on
+    pub fn subscribe(&self) -> SignalStream<D> {
+        let stream = futures_util::stream::unfold(self.sender.subscribe(), |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(data) => return Some((data, receiver)),
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        });
+
+        SignalStream { stream: Box::pin(stream) }
+    }
+
+    pub(crate) fn report(&self, data: D) {
+        let _ = self.sender.send(data);
+    }
+}
+
+#[cfg(feature = "async")]
+impl<D: Clone + Send + 'static> ReportSignal<D> for StreamSignal<D> {
+    fn report_data(&mut self, t: D) {
+        self.report(t);
+    }
+}
+
+/// Reports of a `StreamSignal`, ends once every clone of the signal is dropped
+#[cfg(feature = "async")]
+pub struct SignalStream<D> {
+    stream: futures_util::stream::BoxStream<'static, D>,
+}
+
+#[cfg(feature = "async")]
+impl<D> futures_core::Stream for SignalStream<D> {
+    type Item = D;
+
+    fn poll_next(mut self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Option<D>> {
+        self.stream.as_mut().poll_next(cx)
+    }
+}
+
+// Multi signal
+// Forwards every report to each of its signals
+pub struct MultiSignal<D>
+    where D: 'static {
+    signals: Vec<Box<dyn ReportSignal<D>>>,
+}
+
+impl<D> Clone for MultiSignal<D> {
+    fn clone(&self) -> Self {
+        Self { signals: self.signals.clone() }
+    }
+}
+
+impl<D> Default for MultiSignal<D> {
+    fn default() -> Self {
+        Self { signals: Vec::new() }
+    }
+}
+
+impl<D> MultiSignal<D> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_signal(&mut self, signal: Box<dyn ReportSignal<D>>) {
+        self.signals.push(signal);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.signals.is_empty()
+    }
+}
+
+impl<D: Clone> ReportSignal<D> for MultiSignal<D> {
+    fn report_data(&mut self, t: D) {
+        for signal in self.signals.iter_mut() {
+            signal.report_data(t.clone());
+        }
+    }
+}
+
+/// Events of one container handled by one worker
+#[derive(Clone)]
+pub(crate) struct ContainerEvents {
+    pub(crate) signal: MultiSignal<TransferEvent>,
+    pub(crate) worker: usize,
+    pub(crate) index: u32,
+}
+
+impl ContainerEvents {
+    pub(crate) fn started(&mut self) {
+        self.signal.report_data(TransferEvent::ContainerStarted { worker: self.worker, index: self.index });
+    }
+
+    pub(crate) fn slot_reserved(&mut self, size: u64) {
+        self.signal.report_data(TransferEvent::SlotReserved { worker: self.worker, index: self.index, size });
+    }
+
+    pub(crate) fn chunk_encrypted(&mut self, chunk: u64, wire_bytes: u64, plain_bytes: u64) {
+        self.signal.report_data(TransferEvent::ChunkEncrypted { worker: self.worker, index: self.index, chunk, wire_bytes, plain_bytes });
+    }
+
+    pub(crate) fn chunk_decrypted(&mut self, chunk: u64, wire_bytes: u64, plain_bytes: u64) {
+        self.signal.report_data(TransferEvent::ChunkDecrypted { worker: self.worker, index: self.index, chunk, wire_bytes, plain_bytes });
+    }
+
+    pub(crate) fn committed(&mut self, locator: String) {
+        self.signal.report_data(TransferEvent::ContainerCommitted { worker: self.worker, index: self.index, locator });
+    }
+
+    pub(crate) fn retry(&mut self, attempt: u32, error: String) {
+        self.signal.report_data(TransferEvent::Retry { worker: self.worker, index: self.index, attempt, error });
+    }
+
+    pub(crate) fn failed(&mut self, error: String) {
+        self.signal.report_data(TransferEvent::ContainerFailed { worker: self.worker, index: self.index, error });
+    }
+}
+
+const DEFAULT_STATS_WINDOW: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WorkerStats {
+    pub worker: usize,
+    pub wire_bytes: u64,
+    pub plain_bytes: u64,
+    /// Plaintext bytes per second over the sliding window
+    pub rate: f64,
+    pub retries: u32,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StatsSnapshot {
+    pub elapsed: Duration,
+
+    /// Bytes sent or received, including retried chunks and chunk metadata
+    pub wire_bytes: u64,
+    /// File bytes done, each chunk is only counted once
+    pub plain_bytes: u64,
+    pub total_bytes: u64,
+
+    /// Plaintext bytes per second over the sliding window
+    pub rate: f64,
+    /// Wire bytes per second over the sliding window
+    pub wire_rate: f64,
+    /// Plaintext bytes per second since the start
+    pub average_rate: f64,
+    /// None while the rate is unknown
+    pub eta: Option<Duration>,
+
+    pub retries: u32,
+    pub committed_containers: u32,
+    pub failed_containers: u32,
+    pub done: bool,
+
+    pub workers: Vec<WorkerStats>,
+}
+
+#[derive(Debug)]
+struct StatsState {
+    window: Duration,
+    start: Instant,
+    total_bytes: u64,
+
+    // (time, worker, wire bytes, plain bytes) reported within the window
+    samples: VecDeque<(Instant, usize, u64, u64)>,
+    // next chunk to count in plain_bytes for each container in progress,
+    // chunks below it are retries
+    counted_chunks: HashMap<u32, u64>,
+
+    wire_bytes: u64,
+    plain_bytes: u64,
+    retries: u32,
+    committed_containers: u32,
+    failed_containers: u32,
+    done: bool,
+
+    workers: BTreeMap<usize, WorkerStats>,
+}
+
+impl StatsState {
+    fn record(&mut self, event: TransferEvent, now: Instant) {
+        match event {
+            TransferEvent::ChunkEncrypted { worker, index, chunk, wire_bytes, plain_bytes } |
+            TransferEvent::ChunkDecrypted { worker, index, chunk, wire_bytes, plain_bytes } => {
+                // a container uploaded again reports its chunks again
+                let counted = self.counted_chunks.entry(index).or_insert(chunk);
+                let plain_bytes = if chunk >= *counted { plain_bytes } else { 0 };
+                *counted = (*counted).max(chunk + 1);
+
+                self.wire_bytes += wire_bytes;
+                self.plain_bytes += plain_bytes;
+
+                let stats = self.worker(worker);
+                stats.wire_bytes += wire_bytes;
+                stats.plain_bytes += plain_bytes;
+
+                self.samples.push_back((now, worker, wire_bytes, plain_bytes));
+            }
+            TransferEvent::Retry { worker, .. } => {
+                self.retries += 1;
+                self.worker(worker).retries += 1;
+            }
+            TransferEvent::ContainerCommitted { index, .. } => {
+                self.counted_chunks.remove(&index);
+                self.committed_containers += 1;
+            }
+            TransferEvent::ContainerFailed { index, .. } => {
+                self.counted_chunks.remove(&index);
+                self.failed_containers += 1;
+            }
+            TransferEvent::TransferDone => self.done = true,
+            TransferEvent::ContainerStarted { worker, .. } |
+            TransferEvent::SlotReserved { worker, .. } => {
+                self.worker(worker);
+            }
+        }
+
+        self.prune(now);
+    }
+
+    fn worker(&mut self, worker: usize) -> &mut WorkerStats {
+        self.workers.entry(worker).or_insert_with(|| WorkerStats { worker, ..WorkerStats::default() })
+    }
+
+    fn prune(&mut self, now: Instant) {
+        while let Some((time, ..)) = self.samples.front() {
+            if now.duration_since(*time) <= self.window {
+                break;
+            }
+
+            self.samples.pop_front();
+        }
+    }
+
+    fn snapshot(&mut self, now: Instant) -> StatsSnapshot {
+        self.prune(now);
+
+        let elapsed = now.duration_since(self.start);
+
+        // the window is shorter than configured during the first seconds
+        let window = elapsed.min(self.window).as_secs_f64();
+
+        let rate_of = |bytes: u64| if window > 0.0 { bytes as f64 / window } else { 0.0 };
+
+        let mut wire = 0;
+        let mut plain = 0;
+        let mut worker_plain: BTreeMap<usize, u64> = BTreeMap::new();
+
+        for (_, worker, wire_bytes, plain_bytes) in self.samples.iter() {
+            wire += wire_bytes;
+            plain += plain_bytes;
+            *worker_plain.entry(*worker).or_default() += plain_bytes;
+        }
+
+        let workers = self.workers.values()
+            .map(|stats| WorkerStats {
+                rate: rate_of(worker_plain.get(&stats.worker).copied().unwrap_or_default()),
+                ..stats.clone()
+            })
+            .collect();
+
+        let rate = rate_of(plain);
+        let remaining = self.total_bytes.saturating_sub(self.plain_bytes);
+
+        let eta = if remaining == 0 {
+            Some(Duration::ZERO)
+        } else if rate > 0.0 {
+            Some(Duration::from_secs_f64(remaining as f64 / rate))
+        } else {
+            None
+        };
+
+        StatsSnapshot {
+            elapsed,
+            wire_bytes: self.wire_bytes,
+            plain_bytes: self.plain_bytes,
+            total_bytes: self.total_bytes,
+            rate,
+            wire_rate: rate_of(wire),
+            average_rate: if elapsed.is_zero() { 0.0 } else { self.plain_bytes as f64 / elapsed.as_secs_f64() },
+            eta,
+            retries: self.retries,
+            committed_containers: self.committed_containers,
+            failed_containers: self.failed_containers,
+            done: self.done,
+            workers,
+        }
+    }
+}
+
+// Transfer statistics
+// Consumes transfer events to compute the rate over a sliding window,
+// the ETA, per-worker throughput and retry counts.
+// Clones share the same statistics.
+#[derive(Clone, Debug)]
+pub struct TransferStats {
+    state: Arc<Mutex<StatsState>>,
+}
+
+impl TransferStats {
+    /// `total_bytes` is the size of the file, used for the ETA
+    pub fn new(total_bytes: u64) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(StatsState {
+                window: DEFAULT_STATS_WINDOW,
+                start: Instant::now(),
+                total_bytes,
+                samples: VecDeque::new(),
+                counted_chunks: HashMap::new(),
+                wire_bytes: 0,
+                plain_bytes: 0,
+                retries: 0,
+                committed_containers: 0,
+                failed_containers: 0,
+                done: false,
+                workers: BTreeMap::new(),
+            })),
+        }
+    }
+
+    /// Duration over which the rates are computed, 5 seconds by default
+    pub fn with_window(self, window: Duration) -> Self {
+        self.state.lock().unwrap().window = window;
+        self
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        self.state.lock().unwrap().snapshot(Instant::now())
+    }
+}
+
+impl ReportSignal<TransferEvent> for TransferStats {
+    fn report_data(&mut self, t: TransferEvent) {
+        self.state.lock().unwrap().record(t, Instant::now());
+    }
+}
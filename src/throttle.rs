@@ -0,0 +1,189 @@
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const SECONDS_PER_DAY: u32 = 24 * 60 * 60;
+
+/// Rate applied between `start` and `end`, in seconds since midnight UTC.
+/// A window with `start > end` wraps around midnight.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateWindow {
+    pub start: u32,
+    pub end: u32,
+    pub bytes_per_second: u64,
+}
+
+impl RateWindow {
+    pub fn new(start: u32, end: u32, bytes_per_second: u64) -> Self {
+        Self { start, end, bytes_per_second }
+    }
+
+    fn contains(&self, second_of_day: u32) -> bool {
+        if self.start <= self.end {
+            second_of_day >= self.start && second_of_day < self.end
+        } else {
+            second_of_day >= self.start || second_of_day < self.end
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    // 0 means unlimited
+    bytes_per_second: u64,
+    schedule: Vec<RateWindow>,
+
+    // negative when a read went over the available tokens
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Bucket {
+    fn current_rate(&self) -> u64 {
+        if self.schedule.is_empty() {
+            return self.bytes_per_second;
+        }
+
+        let second_of_day = (SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() % SECONDS_PER_DAY as u64) as u32;
+
+        self.schedule.iter()
+            .find(|window| window.contains(second_of_day))
+            .map(|window| window.bytes_per_second)
+            .unwrap_or(self.bytes_per_second)
+    }
+}
+
+// Token bucket rate limiter
+// Clones share the same bucket, so one limiter can be
+// given to every thread of a transfer or to several transfers.
+// The bucket holds at most one second worth of bytes.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+impl RateLimiter {
+    /// `bytes_per_second` of 0 means unlimited
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bucket: Arc::new(Mutex::new(Bucket {
+                bytes_per_second,
+                schedule: Vec::new(),
+                tokens: bytes_per_second as f64,
+                last_refill: Instant::now(),
+            })),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(0)
+    }
+
+    /// Limiter applied to every transfer in addition to their own limiter,
+    /// unlimited until a rate is set.
+    pub fn global() -> &'static RateLimiter {
+        static GLOBAL: OnceLock<RateLimiter> = OnceLock::new();
+
+        GLOBAL.get_or_init(RateLimiter::unlimited)
+    }
+
+    pub fn set_rate(&self, bytes_per_second: u64) {
+        self.bucket.lock().unwrap().bytes_per_second = bytes_per_second;
+    }
+
+    /// Windows override the base rate while they are active,
+    /// the first matching window wins.
+    pub fn set_schedule(&self, schedule: Vec<RateWindow>) {
+        self.bucket.lock().unwrap().schedule = schedule;
+    }
+
+    pub fn get_rate(&self) -> u64 {
+        self.bucket.lock().unwrap().current_rate()
+    }
+
+    /// Take `bytes` tokens from the bucket, sleeping until the
+    /// bucket is no longer in debt.
+    pub fn acquire(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+
+            let rate = bucket.current_rate();
+
+            if rate == 0 {
+                return;
+            }
+
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+
+            bucket.last_refill = now;
+            bucket.tokens = (bucket.tokens + elapsed * rate as f64).min(rate as f64);
+            bucket.tokens -= bytes as f64;
+
+            if bucket.tokens >= 0.0 {
+                return;
+            }
+
+            Duration::from_secs_f64(-bucket.tokens / rate as f64)
+        };
+
+        sleep(wait);
+    }
+}
+
+/// Apply the transfer limiter, if any, then the global one
+pub(crate) fn throttle(limiter: &Option<RateLimiter>, bytes: usize) {
+    if let Some(limiter) = limiter {
+        limiter.acquire(bytes);
+    }
+
+    RateLimiter::global().acquire(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use crate::throttle::{RateLimiter, RateWindow};
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(100_000);
+
+        let start = Instant::now();
+
+        // the first 100KB are the initial burst, the next 50KB take half a second
+        for _ in 0..15 {
+            limiter.acquire(10_000);
+        }
+
+        let elapsed = start.elapsed();
+
+        assert!(elapsed >= Duration::from_millis(450), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(1000), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let limiter = RateLimiter::unlimited();
+
+        let start = Instant::now();
+
+        limiter.acquire(1 << 30);
+
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_windows() {
+        let day = RateWindow::new(8 * 3600, 20 * 3600, 10);
+
+        assert!(day.contains(12 * 3600));
+        assert!(!day.contains(22 * 3600));
+
+        let night = RateWindow::new(22 * 3600, 6 * 3600, 10);
+
+        assert!(night.contains(23 * 3600));
+        assert!(night.contains(3600));
+        assert!(!night.contains(12 * 3600));
+    }
+}
@@ -1,24 +1,24 @@
 use std::cmp::{min};
 use std::collections::VecDeque;
 use std::marker::Send;
-use std::fs::{File, metadata};
-use std::io::{Read, Seek, SeekFrom};
-use std::sync::{Arc, Mutex};
-use aes::{Aes256};
-use block_modes::block_padding::Pkcs7;
-use block_modes::{BlockMode, Cbc};
-use hmac::Hmac;
-use pbkdf2::pbkdf2;
-use reqwest::blocking::{Body, Client};
+use std::fs::{File, OpenOptions, metadata};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::sleep;
+use std::time::Duration;
+use reqwest::blocking::{Body, Client, RequestBuilder};
 use serde_json::json;
 use sha2::{Digest, Sha256};
 use threadpool::ThreadPool;
 use rand::{RngCore, thread_rng};
-use crate::common::{Container, Waterfall, FileReadable, FileWritable, ResumableFileUpload};
-use crate::http_client::{create_client, prepare_discord_request};
-use crate::signal::{LinearPartSignal, PartProgression, ProgressionRange, ReportSignal};
-
-type Aes256Cbc = Cbc<Aes256, Pkcs7>;
+use crate::chunk::{container_count, container_layout, derive_key, encrypt_chunk};
+use crate::common::{Container, Waterfall, WaterfallFormat, FileReadable, FileWritable, ResumableFileUpload, Subscription, DEFAULT_CHUNK_SIZE, DEFAULT_KDF_ITERATIONS, MAX_CHUNK_SIZE, METADATA_SIZE, MIN_CHUNK_SIZE};
+use crate::control::TransferControl;
+use crate::credentials::{check_status, Credential, CredentialPool};
+use crate::throttle::{throttle, RateLimiter};
+use crate::http_client::{default_client, prepare_discord_request, upload_headers, webhook_headers, ClientProfile};
+use crate::signal::{ContainerEvents, LinearPartSignal, MultiSignal, PartProgression, ProgressionRange, ReportSignal, TransferEvent};
 
 pub trait Uploader<T, R>
     where T: Sized + Clone
@@ -27,44 +27,85 @@ pub trait Uploader<T, R>
 }
 
 pub trait WaterfallExporter {
-    fn export_waterfall(&self) -> Waterfall;
-    fn export_waterfall_with_password(&self, password: String) -> Waterfall;
+    fn export_waterfall(&self) -> std::io::Result<Waterfall>;
+    fn export_waterfall_with_password(&self, password: String) -> std::io::Result<Waterfall>;
 }
 
 pub trait ResumableUploader<T>
     where T: FileWritable + FileReadable + Clone {
-    fn export_resume_session(&self) -> T;
+    fn export_resume_session(&self) -> std::io::Result<T>;
 
     fn from_resume_session(resume_session: T) -> std::io::Result<Self>
         where Self: Sized;
 }
 
-const CHUNK_SIZE: u32 = 1 << 16;
-
-const METADATA_SIZE: usize = 64;
+const DEFAULT_RETRIES: u32 = 3;
 
 pub struct FileUploader {
     file_path: String,
     file_size: u64,
 
     container_size: u32,
+    chunk_size: u32,
 
     remaining_container_indexes: Arc<Mutex<VecDeque<u32>>>,
     current_downloading_indexes: Arc<Mutex<Vec<u32>>>,
     containers: Arc<Mutex<Vec<Container>>>,
+    failed_containers: Arc<Mutex<Vec<(u32, String)>>>,
+    /// SHA-256 of the file once computed, see `get_file_hash`
+    file_hash: Arc<Mutex<Option<[u8; 32]>>>,
 
     pool: Arc<ThreadPool>,
 }
 
 impl FileUploader {
+    /// Chunks must be a power of two between `MIN_CHUNK_SIZE` and `MAX_CHUNK_SIZE`
+    pub fn check_chunk_size(chunk_size: u32) -> std::io::Result<()> {
+        if !chunk_size.is_power_of_two() || !(MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&chunk_size) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Chunk size {} is not a power of two between {} and {}", chunk_size, MIN_CHUNK_SIZE, MAX_CHUNK_SIZE)));
+        }
+
+        Ok(())
+    }
+
+    /// Containers hold whole chunks, so their size must be a non-zero multiple of the chunk size
+    pub fn check_container_size(container_size: u64, chunk_size: u32) -> std::io::Result<()> {
+        if container_size == 0 || !container_size.is_multiple_of(chunk_size as u64) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Container size {} is not a multiple of the chunk size {}", container_size, chunk_size)));
+        }
+
+        if container_size > u32::MAX as u64 {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Container size {} is too large", container_size)));
+        }
+
+        Ok(())
+    }
+
+    /// Webhooks post attachments within the limit of an account without subscription,
+    /// whatever the subscription of the owner of the channel
+    pub fn check_webhook_container_size(container_size: u64) -> std::io::Result<()> {
+        let limit = Subscription::Free.get_max_chunk_upload_size() as u64;
+
+        if container_size > limit {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Container size {} is above the webhook limit of {}", container_size, limit)));
+        }
+
+        Ok(())
+    }
+
     pub fn new(file_path: String, container_size: u32) -> FileUploader {
         FileUploader::new_with_threads_count(file_path, container_size, 2)
     }
 
     pub fn new_with_threads_count(file_path: String, container_size: u32, threads_count: u32) -> FileUploader {
+        FileUploader::new_with_chunk_size(file_path, container_size, threads_count, DEFAULT_CHUNK_SIZE)
+    }
+
+    /// Smaller chunks allow finer random access, larger chunks lower the metadata overhead
+    pub fn new_with_chunk_size(file_path: String, container_size: u32, threads_count: u32, chunk_size: u32) -> FileUploader {
         let file_size = Self::file_size(file_path.clone());
 
-        let container_count = Self::container_count(file_size, container_size as u64);
+        let container_count = container_count(file_size, container_size as u64, chunk_size as u64);
         let mut deque: VecDeque<u32> = VecDeque::with_capacity(container_count);
 
         for i in 0..container_count {
@@ -75,48 +116,102 @@ impl FileUploader {
             file_size,
             file_path: file_path.clone(),
             container_size,
+            chunk_size,
             remaining_container_indexes: Arc::new(Mutex::new(deque)),
             containers: Arc::new(Mutex::new(Vec::new())),
             current_downloading_indexes: Arc::new(Mutex::new(Vec::new())),
+            failed_containers: Arc::new(Mutex::new(Vec::new())),
+            file_hash: Arc::new(Mutex::new(None)),
             pool: Arc::new(ThreadPool::new(threads_count as usize)),
         }
     }
 
-    fn file_size(file_path: String) -> u64 {
-        let meta = metadata(file_path).unwrap();
+    pub fn get_file_size(&self) -> u64 {
+        self.file_size
+    }
 
-        meta.len()
+    /// Upload the binary encoding of the waterfall like a file, returning a
+    /// root pointer to it. The root pointer embeds the waterfall password, if any,
+    /// the manifest itself is uploaded without it.
+    pub fn upload_manifest(waterfall: &Waterfall, container_size: u32, chunk_size: u32, arguments: FileUploadArguments) -> std::io::Result<Waterfall> {
+        let mut manifest = waterfall.clone();
+        manifest.password = String::new();
+
+        let manifest_file = ManifestFile::create(&manifest.to_bytes(WaterfallFormat::Binary)?)?;
+
+        let mut uploader = FileUploader::new_with_chunk_size(manifest_file.path.to_string_lossy().to_string(), container_size, 1, chunk_size);
+
+        let handle = uploader.upload(arguments);
+        let results = handle.wait();
+
+        let root = match results.into_iter().find_map(|result| result.result.err().map(|err| (result.index, err))) {
+            Some((index, err)) => Err(std::io::Error::other(format!("Manifest container {} failed: {}", index, err))),
+            None => uploader.export_waterfall_with_password(waterfall.password.clone()),
+        };
+
+        drop(manifest_file);
+
+        let mut root = root?;
+        root.filename = waterfall.filename.clone();
+        root.manifest_level = waterfall.manifest_level + 1;
+
+        Ok(root)
     }
 
-    fn container_count(file_size: u64, container_size: u64) -> usize {
-        let chunk_count = (file_size / (CHUNK_SIZE as u64 - METADATA_SIZE as u64)) + 1;
+    /// Upload manifests until the root pointer fits in a single container
+    pub fn upload_root_pointer(waterfall: &Waterfall, container_size: u32, chunk_size: u32, arguments: FileUploadArguments) -> std::io::Result<Waterfall> {
+        let mut root = Self::upload_manifest(waterfall, container_size, chunk_size, arguments.clone())?;
+
+        while root.containers.len() > 1 {
+            root = Self::upload_manifest(&root, container_size, chunk_size, arguments.clone())?;
+        }
+
+        Ok(root)
+    }
 
-        let chunks_per_container = container_size / (CHUNK_SIZE as u64);
+    fn file_size(file_path: String) -> u64 {
+        let meta = metadata(file_path).unwrap();
 
-        (chunk_count as f64 / chunks_per_container as f64).ceil() as usize
+        meta.len()
     }
 
-    fn file_hash(file_path: String) -> [u8; 32] {
+    fn file_hash(file_path: String) -> std::io::Result<[u8; 32]> {
         let mut hasher = Sha256::new();
         // hash file
-        let mut file = File::open(file_path).unwrap();
+        let mut file = File::open(file_path)?;
         let mut buffer = [0u8; 1024 * 1024];
         loop {
-            let bytes_read = file.read(&mut buffer).unwrap();
+            let bytes_read = file.read(&mut buffer)?;
             if bytes_read == 0 {
                 break;
             }
             hasher.update(&buffer[0..bytes_read]);
         }
-        hasher.finalize().into()
+        Ok(hasher.finalize().into())
+    }
+
+    /// SHA-256 of the file, read once more in full on the first call.
+    /// Containers are uploaded out of order, so the hash cannot be fed by
+    /// the workers; it is kept for later exports and resume sessions instead.
+    fn get_file_hash(&self) -> std::io::Result<[u8; 32]> {
+        let mut file_hash = self.file_hash.lock().unwrap();
+
+        if let Some(hash) = *file_hash {
+            return Ok(hash);
+        }
+
+        let hash = Self::file_hash(self.file_path.clone())?;
+        *file_hash = Some(hash);
+
+        Ok(hash)
     }
 
     fn compute_chunk_count(&self) -> usize {
-        let real_size = CHUNK_SIZE as usize - METADATA_SIZE;
+        let real_size = self.chunk_size as usize - METADATA_SIZE;
 
-        let chunks_per_container = (self.container_size as usize) / (CHUNK_SIZE as usize);
+        let chunks_per_container = (self.container_size as usize) / (self.chunk_size as usize);
 
-        let container = Self::container_count(self.file_size, self.container_size as u64);
+        let container = container_count(self.file_size, self.container_size as u64, self.chunk_size as u64);
 
         // the first N-1 containers are always full:
         let mut chunk_count = (container - 1) * chunks_per_container;
@@ -131,6 +226,41 @@ impl FileUploader {
 
         chunk_count
     }
+
+    /// Containers ordered by index, keeping a single container per index
+    fn sorted_containers(&self) -> Vec<Container> {
+        let mut containers = self.containers.lock().unwrap().clone();
+
+        containers.sort_by_key(|c| c.index);
+        containers.dedup_by_key(|c| c.index);
+
+        containers
+    }
+
+    /// Committed and failed containers, ordered by index
+    fn container_results(&self) -> Vec<ContainerUploadResult> {
+        let mut results: Vec<ContainerUploadResult> = self.sorted_containers().into_iter()
+            .map(|container| ContainerUploadResult { index: container.index, result: Ok(container) })
+            .collect();
+
+        for (index, error) in self.failed_containers.lock().unwrap().iter() {
+            results.push(ContainerUploadResult { index: *index, result: Err(error.clone()) });
+        }
+
+        results.sort_by_key(|r| r.index);
+
+        results
+    }
+
+    /// Put the containers that failed during a previous upload back in the queue
+    fn requeue_failed_containers(&self) {
+        let mut failed = self.failed_containers.lock().unwrap();
+        let mut deque = self.remaining_container_indexes.lock().unwrap();
+
+        for (index, _) in failed.drain(..) {
+            deque.push_back(index);
+        }
+    }
 }
 
 impl Clone for FileUploader {
@@ -139,9 +269,12 @@ impl Clone for FileUploader {
             file_path: self.file_path.clone(),
             file_size: self.file_size,
             container_size: self.container_size,
+            chunk_size: self.chunk_size,
             remaining_container_indexes: Arc::clone(&self.remaining_container_indexes),
             containers: Arc::clone(&self.containers),
             current_downloading_indexes: Arc::clone(&self.current_downloading_indexes),
+            failed_containers: Arc::clone(&self.failed_containers),
+            file_hash: Arc::clone(&self.file_hash),
             pool: Arc::clone(&self.pool),
         }
     }
@@ -149,11 +282,21 @@ impl Clone for FileUploader {
 
 #[derive(Clone)]
 pub struct FileUploadArguments {
-    encryption_password: String,
-    token: String,
-    channel_id: u64,
+    pub(crate) encryption_password: String,
+    pub(crate) credentials: CredentialPool,
+    /// Containers are posted to this webhook instead of the channel
+    pub(crate) webhook_url: Option<String>,
 
     signal: Option<Box<dyn ReportSignal<ProgressionRange<u64>>>>,
+    source_signal: Option<Box<dyn ReportSignal<ProgressionRange<u64>>>>,
+    events: MultiSignal<TransferEvent>,
+    control: TransferControl,
+    rate_limiter: Option<RateLimiter>,
+    pub(crate) retries: u32,
+    pub(crate) kdf_iterations: u32,
+    // the default client is built by the threads, it cannot be built on an async runtime
+    client: Option<Client>,
+    pub(crate) profile: ClientProfile,
     join: bool,
 }
 
@@ -165,15 +308,41 @@ pub struct FileUploadArguments {
 
 impl FileUploadArguments {
     pub fn new(encryption_password: String, token: String, channel_id: u64) -> FileUploadArguments {
+        FileUploadArguments::new_pool(encryption_password, &CredentialPool::new(vec![Credential::new(token, channel_id)]))
+    }
+
+    /// Upload each container with a credential of the pool,
+    /// the pool can be shared with other uploads
+    pub fn new_pool(encryption_password: String, credentials: &CredentialPool) -> FileUploadArguments {
         FileUploadArguments {
             encryption_password,
-            token,
-            channel_id,
+            credentials: credentials.clone(),
+            webhook_url: None,
             signal: None,
+            source_signal: None,
+            events: MultiSignal::new(),
+            control: TransferControl::new(),
+            rate_limiter: None,
+            retries: DEFAULT_RETRIES,
+            kdf_iterations: DEFAULT_KDF_ITERATIONS,
+            client: None,
+            profile: ClientProfile::default(),
             join: true,
         }
     }
 
+    /// Post the containers with a channel webhook, no user token is needed.
+    /// The url is the one given by Discord, `https://discord.com/api/webhooks/<id>/<token>`.
+    /// Containers must fit in a webhook attachment, see `FileUploader::check_webhook_container_size`.
+    pub fn new_webhook(encryption_password: String, webhook_url: String) -> FileUploadArguments {
+        let mut arguments = FileUploadArguments::new_pool(encryption_password, &CredentialPool::new(Vec::new()));
+        arguments.webhook_url = Some(webhook_url);
+
+        arguments
+    }
+
+    /// Report progress in uploaded bytes, chunk metadata and padding included,
+    /// ranges cover `[0, UploadHandle::get_total_size())`
     pub fn with_signal(&mut self, signal: &PartProgression<u64>) -> &Self {
         self.signal = Some(Box::new(signal.clone()));
 
@@ -181,72 +350,282 @@ impl FileUploadArguments {
 
         self
     }
+
+    /// Report progress in bytes of the source file,
+    /// ranges cover `[0, UploadHandle::get_file_size())`
+    pub fn with_source_signal(&mut self, signal: &PartProgression<u64>) -> &Self {
+        self.source_signal = Some(Box::new(signal.clone()));
+
+        self.join = false;
+
+        self
+    }
+
+    /// Can be called several times to report events to several signals
+    pub fn with_events<S>(&mut self, signal: &S) -> &Self
+        where S: ReportSignal<TransferEvent> + Clone + 'static {
+        self.events.add_signal(Box::new(signal.clone()));
+
+        self
+    }
+
+    pub fn with_control(&mut self, control: &TransferControl) -> &Self {
+        self.control = control.clone();
+
+        self
+    }
+
+    /// Number of times a container is uploaded again after a failure
+    pub fn with_retries(&mut self, retries: u32) -> &Self {
+        self.retries = retries;
+
+        self
+    }
+
+    /// PBKDF2 iterations used to derive the key of each container,
+    /// stored in the waterfall
+    pub fn with_kdf_iterations(&mut self, iterations: u32) -> &Self {
+        self.kdf_iterations = iterations;
+
+        self
+    }
+
+    /// Limit the upload rate of all the threads, on top of `RateLimiter::global()`
+    pub fn with_rate_limiter(&mut self, rate_limiter: &RateLimiter) -> &Self {
+        self.rate_limiter = Some(rate_limiter.clone());
+
+        self
+    }
+
+    /// Client shared by all the threads, see `HttpConfig::build`
+    pub fn with_client(&mut self, client: &Client) -> &Self {
+        self.client = Some(client.clone());
+
+        self
+    }
+
+    /// Identity sent to Discord, the Android client by default
+    pub fn with_client_profile(&mut self, profile: &ClientProfile) -> &Self {
+        self.profile = profile.clone();
+
+        self
+    }
+}
+
+/// Outcome of the upload of one container
+#[derive(Clone, Debug)]
+pub struct ContainerUploadResult {
+    pub index: u32,
+    pub result: Result<Container, String>,
+}
+
+struct UploadCompletion {
+    running_workers: Mutex<usize>,
+    finished: Condvar,
+}
+
+/// Decrements the running workers count when a worker exits, even on panic.
+/// The last worker reports the end of the transfer.
+struct WorkerGuard {
+    completion: Arc<UploadCompletion>,
+    events: MultiSignal<TransferEvent>,
+}
+
+unsafe impl Send for WorkerGuard {}
+
+impl Drop for WorkerGuard {
+    fn drop(&mut self) {
+        let mut running_workers = self.completion.running_workers.lock().unwrap_or_else(|e| e.into_inner());
+
+        *running_workers -= 1;
+
+        if *running_workers == 0 {
+            self.events.report_data(TransferEvent::TransferDone);
+        }
+
+        self.completion.finished.notify_all();
+    }
+}
+
+/// Handle on a running upload
+pub struct UploadHandle {
+    total_size: u64,
+    file_size: u64,
+    uploader: FileUploader,
+    completion: Arc<UploadCompletion>,
+    control: TransferControl,
+}
+
+impl UploadHandle {
+    /// Number of bytes sent over the wire once every container is uploaded
+    pub fn get_total_size(&self) -> u64 {
+        self.total_size
+    }
+
+    /// Number of bytes of the source file
+    pub fn get_file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    /// Block until every worker has stopped, then return the result of each container.
+    /// Once this returns, all successful containers are committed.
+    pub fn wait(&self) -> Vec<ContainerUploadResult> {
+        let mut running_workers = self.completion.running_workers.lock().unwrap();
+
+        while *running_workers > 0 {
+            running_workers = self.completion.finished.wait(running_workers).unwrap();
+        }
+
+        self.uploader.container_results()
+    }
+
+    /// Return the result of each container if every worker has stopped, `None` otherwise
+    pub fn try_wait(&self) -> Option<Vec<ContainerUploadResult>> {
+        if *self.completion.running_workers.lock().unwrap() > 0 {
+            return None;
+        }
+
+        Some(self.uploader.container_results())
+    }
+
+    /// Abort the containers being uploaded and stop the workers,
+    /// unfinished containers stay in the resume session
+    pub fn cancel(&self) {
+        self.control.cancel();
+    }
+
+    /// Hold the workers once their current container is done
+    pub fn pause(&self) {
+        self.control.pause();
+    }
+
+    pub fn resume(&self) {
+        self.control.resume();
+    }
 }
 
-impl Uploader<FileUploadArguments, u64> for FileUploader {
+impl Uploader<FileUploadArguments, UploadHandle> for FileUploader {
     /// Upload the file using the arguments
-    /// Returning the number of bytes uploaded
-    /// (Or being uploaded if a signal is passed)
-    fn upload(&mut self, arguments: FileUploadArguments) -> u64 {
-        for _ in 0..self.pool.max_count() {
+    /// Returning a handle on the upload, already finished
+    /// unless a signal is passed
+    fn upload(&mut self, arguments: FileUploadArguments) -> UploadHandle {
+        self.requeue_failed_containers();
+
+        let completion = Arc::new(UploadCompletion {
+            running_workers: Mutex::new(self.pool.max_count()),
+            finished: Condvar::new(),
+        });
+
+        for worker in 0..self.pool.max_count() {
             // create file uploader
-            let mut uploader = FileThreadedUploader::new(
-                self.remaining_container_indexes.clone(),
-                self.file_path.clone(),
-                self.container_size,
-                arguments.clone(),
-                self.file_size,
-                self.containers.clone(),
-                self.current_downloading_indexes.clone(),
-            );
+            let mut uploader = FileThreadedUploader::new(self, arguments.clone(), worker);
+
+            let guard = WorkerGuard { completion: completion.clone(), events: arguments.events.clone() };
 
             self.pool.execute(move || {
+                let _guard = guard;
+
                 uploader.start_uploading();
             });
         }
 
+        let handle = UploadHandle {
+            total_size: self.compute_chunk_count() as u64 * self.chunk_size as u64,
+            file_size: self.file_size,
+            uploader: self.clone(),
+            completion,
+            control: arguments.control.clone(),
+        };
+
         if arguments.join {
-            self.pool.join();
+            handle.wait();
         }
 
-        self.compute_chunk_count() as u64 * CHUNK_SIZE as u64
+        handle
+    }
+}
+
+/// Manifest written for the upload, readable by its owner only
+/// and removed once dropped, the upload failing included
+struct ManifestFile {
+    path: PathBuf,
+}
+
+impl ManifestFile {
+    fn create(bytes: &[u8]) -> std::io::Result<ManifestFile> {
+        let path = std::env::temp_dir().join(format!("discord-us-manifest-{:032x}", rand::random::<u128>()));
+
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        let manifest_file = ManifestFile { path };
+        options.open(&manifest_file.path)?.write_all(bytes)?;
+
+        Ok(manifest_file)
+    }
+}
+
+impl Drop for ManifestFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
     }
 }
 
 impl WaterfallExporter for FileUploader {
-    fn export_waterfall(&self) -> Waterfall {
+    fn export_waterfall(&self) -> std::io::Result<Waterfall> {
         self.export_waterfall_with_password(String::new())
     }
 
 
-    fn export_waterfall_with_password(&self, password: String) -> Waterfall {
-        let containers = self.containers.lock().unwrap().clone();
+    /// Fails while some containers are still pending, being uploaded or have failed,
+    /// a resume session should be exported instead.
+    fn export_waterfall_with_password(&self, password: String) -> std::io::Result<Waterfall> {
+        let pending = self.remaining_container_indexes.lock().unwrap().len();
+        let in_flight = self.current_downloading_indexes.lock().unwrap().len();
+        let failed = self.failed_containers.lock().unwrap().len();
 
-        Waterfall {
-            containers,
+        if pending > 0 || in_flight > 0 || failed > 0 {
+            return Err(std::io::Error::other(format!("Upload is not finished ({} containers pending, {} in flight, {} failed)", pending, in_flight, failed)));
+        }
+
+        let waterfall = Waterfall {
+            containers: self.sorted_containers(),
             size: self.file_size,
             filename: self.file_path.clone(),
             password: password.clone(),
-        }
+            file_hash: Some(self.get_file_hash()?),
+            manifest_level: 0,
+        };
+
+        waterfall.validate()?;
+
+        Ok(waterfall)
     }
 }
 
 impl ResumableUploader<ResumableFileUpload> for FileUploader {
-    fn export_resume_session(&self) -> ResumableFileUpload {
+    /// Fails when the file cannot be hashed, e.g. moved since the upload started
+    fn export_resume_session(&self) -> std::io::Result<ResumableFileUpload> {
         // Collect remaining indexes
         let remaining_container_indexes = self.remaining_container_indexes.lock().unwrap().clone();
 
         // Collect containers
-        let containers = self.containers.lock().unwrap().clone();
+        let containers = self.sorted_containers();
 
         // collect working indexes
         let working_indexes = self.current_downloading_indexes.lock().unwrap().clone();
 
+        // collect failed indexes
+        let failed_indexes: Vec<u32> = self.failed_containers.lock().unwrap().iter().map(|(index, _)| *index).collect();
+
         // construct file hash
-        let file_hash = Self::file_hash(self.file_path.clone());
+        let file_hash = self.get_file_hash()?;
 
         // push all remaining indexes
-        let mut remaining_indexes = Vec::with_capacity(remaining_container_indexes.len() + working_indexes.len());
+        let mut remaining_indexes = Vec::with_capacity(remaining_container_indexes.len() + working_indexes.len() + failed_indexes.len());
 
         for index in remaining_container_indexes {
             remaining_indexes.push(index);
@@ -256,15 +635,20 @@ impl ResumableUploader<ResumableFileUpload> for FileUploader {
             remaining_indexes.push(index);
         }
 
-        ResumableFileUpload {
+        for index in failed_indexes {
+            remaining_indexes.push(index);
+        }
+
+        Ok(ResumableFileUpload {
             file_path: self.file_path.clone(),
             file_size: self.file_size,
             container_size: self.container_size,
+            chunk_size: self.chunk_size,
             remaining_indexes,
             containers,
             file_hash,
             thread_count: self.pool.max_count(),
-        }
+        })
     }
 
     fn from_resume_session(resume_session: ResumableFileUpload) -> std::io::Result<Self>
@@ -275,7 +659,7 @@ impl ResumableUploader<ResumableFileUpload> for FileUploader {
             return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "File size mismatch"));
         }
 
-        let file_hash = Self::file_hash(resume_session.file_path.clone());
+        let file_hash = Self::file_hash(resume_session.file_path.clone())?;
 
         if file_hash != resume_session.file_hash {
             return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "File hash mismatch"));
@@ -285,9 +669,13 @@ impl ResumableUploader<ResumableFileUpload> for FileUploader {
             file_path: resume_session.file_path.clone(),
             file_size,
             container_size: resume_session.container_size,
+            chunk_size: resume_session.chunk_size,
             remaining_container_indexes: Arc::new(Mutex::new(VecDeque::from(resume_session.remaining_indexes.clone()))),
             current_downloading_indexes: Arc::new(Mutex::new(Vec::new())),
             containers: Arc::new(Mutex::new(resume_session.containers.clone())),
+            failed_containers: Arc::new(Mutex::new(Vec::new())),
+            // checked against the file just above
+            file_hash: Arc::new(Mutex::new(Some(file_hash))),
             pool: Arc::new(ThreadPool::new(resume_session.thread_count)),
         };
 
@@ -301,6 +689,7 @@ struct FileThreadedUploader {
     file_path: String,
     file_size: u64,
     container_size: u32,
+    chunk_size: u32,
 
     arguments: FileUploadArguments,
 
@@ -308,142 +697,192 @@ struct FileThreadedUploader {
 
     containers: Arc<Mutex<Vec<Container>>>,
     current_downloading_indexes: Arc<Mutex<Vec<u32>>>,
+    failed_containers: Arc<Mutex<Vec<(u32, String)>>>,
+
+    worker: usize,
 }
 
 unsafe impl Send for FileThreadedUploader {}
 
 impl FileThreadedUploader {
-    fn new(current_container_index: Arc<Mutex<VecDeque<u32>>>,
-           file_path: String,
-           container_size: u32,
-           arguments: FileUploadArguments,
-           file_size: u64,
-           containers: Arc<Mutex<Vec<Container>>>,
-           current_downloading_indexes: Arc<Mutex<Vec<u32>>>,
-    ) -> FileThreadedUploader {
+    fn new(uploader: &FileUploader, arguments: FileUploadArguments, worker: usize) -> FileThreadedUploader {
         FileThreadedUploader {
-            container_size,
-            file_path,
-            current_container_index,
+            container_size: uploader.container_size,
+            chunk_size: uploader.chunk_size,
+            file_path: uploader.file_path.clone(),
+            current_container_index: uploader.remaining_container_indexes.clone(),
+            client: arguments.client.clone().unwrap_or_else(default_client),
             arguments,
-            file_size,
-            client: create_client(),
-            containers,
-            current_downloading_indexes,
+            file_size: uploader.file_size,
+            containers: uploader.containers.clone(),
+            current_downloading_indexes: uploader.current_downloading_indexes.clone(),
+            failed_containers: uploader.failed_containers.clone(),
+            worker,
         }
     }
 
     fn start_uploading(&mut self) {
         while let Some(container_index) = self.get_processing_container_index() {
-            self.set_current_downloading_index(container_index);
-
             //println!("Uploading Container {:?}", container_index);
 
-            let container = self.upload(container_index);
+            let mut events = ContainerEvents {
+                signal: self.arguments.events.clone(),
+                worker: self.worker,
+                index: container_index,
+            };
+
+            events.started();
 
-            self.add_container(container);
+            match self.upload_with_retries(container_index, &mut events) {
+                Ok(container) => {
+                    events.committed(container.storage_url.clone());
+                    self.add_container(container);
+                }
+                // an aborted container is not a failure, it is uploaded again on resume
+                Err(_) if self.arguments.control.is_cancelled() => self.add_remaining_container(container_index),
+                Err(err) => {
+                    events.failed(err.to_string());
+                    self.add_failed_container(container_index, err.to_string());
+                }
+            }
 
             self.remove_current_downloading_index(container_index);
         }
-
-        return;
     }
 
-    fn upload(&mut self, container_index: u32) -> Container {
-        let filename = "data.enc".to_string();
-
-        let mut salt = [0u8; 16];
-
-        //println!("Doing upload of index {:?}", container_index);
-
-        thread_rng().fill_bytes(&mut salt);
-
-        let mut key = [0u8; 32];
-        pbkdf2::<Hmac<Sha256>>(self.arguments.encryption_password.as_bytes(), &salt, 10000, &mut key);
-
+    fn upload_with_retries(&mut self, container_index: u32, events: &mut ContainerEvents) -> std::io::Result<Container> {
+        // would be rejected by Discord on every attempt
+        if self.arguments.webhook_url.is_some() {
+            FileUploader::check_webhook_container_size(self.container_size as u64)?;
+        }
 
-        //println!("Computing cursor chunks_per_container: {:?}", self.chunks_per_container());
+        let mut attempt = 0;
 
-        //let cursor = (((container_index - 1) * self.container_size) as i64) - ((METADATA_SIZE as i64) * (max(0, (container_index as i64) - 2)) * (self.chunks_per_container() as i64));
-        let cursor = (container_index as i64 - 1) * self.chunks_per_container() as i64 * (CHUNK_SIZE as i64 - METADATA_SIZE as i64);
+        loop {
+            match self.upload(container_index, events) {
+                Ok(container) => return Ok(container),
+                Err(err) if attempt >= self.arguments.retries || self.arguments.control.is_cancelled() => return Err(err),
+                Err(err) => {
+                    attempt += 1;
 
-        //println!("cursor: {:?}", cursor);
+                    events.retry(attempt, err.to_string());
 
-        let remaining_real_size = self.file_size - cursor as u64;
-        let remaining_extra_padding = ((remaining_real_size / (CHUNK_SIZE as u64 - METADATA_SIZE as u64)) + 1) * METADATA_SIZE as u64;
+                    sleep(Duration::from_secs(attempt as u64));
+                }
+            }
+        }
+    }
 
-        //println!("Remaining real size: {:?} (extra padding {:?}", remaining_real_size, remaining_extra_padding);
+    fn upload(&mut self, container_index: u32, events: &mut ContainerEvents) -> std::io::Result<Container> {
+        let filename = "data.enc".to_string();
 
-        let mut remaining_size = min(self.container_size as u64, remaining_real_size + remaining_extra_padding);
+        let mut salt = [0u8; 16];
 
-        if remaining_size % (CHUNK_SIZE as u64) > 0 {
-            remaining_size += (CHUNK_SIZE as u64) - remaining_size % (CHUNK_SIZE as u64);
-        }
+        //println!("Doing upload of index {:?}", container_index);
 
-        //println!("Remaining size: {:?}", remaining_size);
+        thread_rng().fill_bytes(&mut salt);
 
-        //println!("Requesting attachment");
-        let (upload_url, upload_filename) = self.request_attachment(filename.clone(), remaining_size);
+        let key = derive_key(&self.arguments.encryption_password, &salt, self.arguments.kdf_iterations);
 
-        //println!("Got upload url: {:?}", upload_url);
+        let chunk_size = self.chunk_size as u64;
+        let layout = container_layout(self.file_size, self.container_size, self.chunk_size, container_index);
+        let (cursor, remaining_size) = (layout.cursor, layout.size);
 
         let report_signal =
             if let Some(signal) = self.arguments.signal.clone() {
-                let cursor_with_metadata = ((container_index as u64) - 1) * self.chunks_per_container() as u64 * (CHUNK_SIZE as u64);
+                let cursor_with_metadata = ((container_index as u64) - 1) * self.chunks_per_container() as u64 * chunk_size;
                 Some(Box::new(LinearPartSignal::new(signal.clone(), cursor_with_metadata)) as Box<dyn ReportSignal<u64>>)
             } else {
                 None
             };
 
+        let mut file = File::open(&self.file_path)?;
+        file.seek(SeekFrom::Start(cursor))?;
+
         let file_uploader = CustomBody::new(
             key,
             remaining_size as i64,
-            self.file_path.clone(),
-            cursor,
+            file,
+            self.chunk_size,
             report_signal,
+            &self.arguments,
+            events.clone(),
         );
 
-        let body = Body::sized(file_uploader, remaining_size);
+        let (message_id, storage_url, credential) = match self.arguments.webhook_url.clone() {
+            // the container is sent with the message itself
+            Some(webhook_url) => {
+                events.slot_reserved(remaining_size);
 
+                let (message_id, storage_url) = self.post_webhook(&webhook_url, &filename, file_uploader, remaining_size)?;
 
-        self.client.put(upload_url)
-            .header("accept-encoding", "gzip")
-            .header("connection", "Keep-Alive")
-            .header("content-length", remaining_size)
-            .header("content-type", "application/x-x509-ca-cert")
-            .header("host", "discord-attachments-uploads-prd.storage.googleapis.com")
-            .header("user-agent", "Discord-Android/192013;RNA")
-            .body(body).send().unwrap();
+                (message_id, storage_url, None)
+            }
+            None => {
+                let lease = self.arguments.credentials.acquire()
+                    .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::PermissionDenied, "Every credential is disabled"))?;
+
+                let result = self.post_container(&lease.credential, filename, file_uploader, remaining_size, events);
+
+                match &result {
+                    Ok(_) => lease.uploaded(),
+                    Err(err) => lease.failed(err),
+                }
 
-        let storage_url = self.post_message(filename.clone(), upload_filename);
+                let (message_id, storage_url) = result?;
 
-        //println!("Computing byte range end (cursor: {:?}, remaining_size: {:?}, file_size {:?}, metadata size: {:?})", cursor, remaining_size, self.file_size, (remaining_size / CHUNK_SIZE as u64) * METADATA_SIZE as u64);
-        let byte_range_end = min(self.file_size, cursor as u64 + remaining_size - ((remaining_size / CHUNK_SIZE as u64) * METADATA_SIZE as u64));
+                (message_id, storage_url, Some(lease.credential.clone()))
+            }
+        };
 
-        return Container {
+        Ok(Container {
+            index: container_index,
             storage_url,
-            chunk_count: remaining_size / CHUNK_SIZE as u64,
-            chunk_size: CHUNK_SIZE as u64,
+            message_id: Some(message_id),
+            channel_id: credential.as_ref().map(|credential| credential.channel_id),
+            credential: credential.map(|credential| credential.name).filter(|name| !name.is_empty()),
+            chunk_count: remaining_size / chunk_size,
+            chunk_size,
             salt,
+            kdf_iterations: self.arguments.kdf_iterations,
             bytes_range: [
-                cursor as u64,
-                byte_range_end
+                cursor,
+                layout.end
             ],
-        };
+        })
+    }
+
+    /// Reserve an attachment, send the container to it and post its message
+    fn post_container(&self, credential: &Credential, filename: String, body: CustomBody, size: u64, events: &mut ContainerEvents) -> std::io::Result<(u64, String)> {
+        let (upload_url, upload_filename) = self.request_attachment(credential, filename.clone(), size)?;
+
+        events.slot_reserved(size);
+
+        self.client.put(upload_url)
+            .headers(upload_headers(size, &self.arguments.profile))
+            .body(Body::sized(body, size)).send()
+            .and_then(|response| response.error_for_status())
+            .map_err(std::io::Error::other)?;
+
+        self.post_message(credential, filename, upload_filename)
     }
 
+    /// Take the next index and mark it as being uploaded in a single step,
+    /// so the index is never missing from both lists
     fn get_processing_container_index(&mut self) -> Option<u32> {
+        if !self.arguments.control.wait_while_paused() {
+            return None;
+        }
+
         let mut deque = self.current_container_index.lock().unwrap();
 
         //println!("Trying to find work! (remaining indexes : {:?}", deque);
 
-        deque.pop_front()
-    }
+        let index = deque.pop_front()?;
 
-    fn set_current_downloading_index(&mut self, index: u32) {
-        let mut deque = self.current_downloading_indexes.lock().unwrap();
+        self.current_downloading_indexes.lock().unwrap().push(index);
 
-        deque.push(index);
+        Some(index)
     }
 
     fn remove_current_downloading_index(&mut self, index: u32) {
@@ -458,83 +897,196 @@ impl FileThreadedUploader {
         deque.push(container);
     }
 
+    fn add_remaining_container(&mut self, index: u32) {
+        let mut deque = self.current_container_index.lock().unwrap();
+
+        deque.push_front(index);
+    }
+
+    fn add_failed_container(&mut self, index: u32, error: String) {
+        let mut failed = self.failed_containers.lock().unwrap();
+
+        failed.push((index, error));
+    }
+
     fn chunks_per_container(&self) -> u32 {
-        self.container_size / CHUNK_SIZE
+        self.container_size / self.chunk_size
     }
 
-    fn request_attachment(&self, filename: String, size: u64) -> (String, String) {
+    fn request_attachment(&self, credential: &Credential, filename: String, size: u64) -> std::io::Result<(String, String)> {
         //println!("Requesting attachment of size {:?}", size);
 
-        let url = format!("https://discord.com/api/v9/channels/{}/attachments", self.arguments.channel_id);
-
-        let payload = json!(
-            {
-                "files": [
-                    {
-                        "filename": filename,
-                        "file_size": size,
-                        "id": "8"
-                    }
-                ]
-            }
-        );
+        let mut request = self.client.post(attachments_url(credential.channel_id));
 
+        request = prepare_discord_request(request, credential.token.clone(), &self.arguments.profile);
 
-        let mut request = self.client.post(url);
+        let resp = discord_json(request.json(&attachment_payload(filename, size)))?;
 
-        request = prepare_discord_request(request, self.arguments.token.clone());
+        parse_attachment(&resp)
+    }
 
-        let resp = request.json(&payload).send().unwrap().json::<serde_json::Value>().unwrap();
+    fn post_webhook(&self, webhook_url: &str, filename: &str, body: CustomBody, size: u64) -> std::io::Result<(u64, String)> {
+        let form = WebhookForm::new(filename);
+        let form_size = form.size(size);
 
-        let upload_url = resp["attachments"][0]["upload_url"].as_str().unwrap();
-        let upload_filename = resp["attachments"][0]["upload_filename"].as_str().unwrap();
+        let body = Cursor::new(form.head).chain(body).chain(Cursor::new(form.tail));
 
-        return (upload_url.to_string(), upload_filename.to_string());
+        let resp = discord_json(self.client.post(webhook_execute_url(webhook_url))
+            .headers(webhook_headers(&form.content_type, form_size, &self.arguments.profile))
+            .body(Body::sized(body, form_size)))?;
+
+        parse_message(&resp)
     }
 
-    fn post_message(&self, filename: String, upload_filename: String) -> String {
+    fn post_message(&self, credential: &Credential, filename: String, upload_filename: String) -> std::io::Result<(u64, String)> {
         // println!("Sending message with filename {:?} and upload_filename {:?}", filename, upload_filename);
 
-        let url = format!("https://discord.com/api/v9/channels/{}/messages", self.arguments.channel_id);
-
-        let payload = json!(
-            {
-                "content": "",
-                "channel_id": self.arguments.channel_id,
-                "type": 0,
-                "attachments": [
-                    {
-                        "id": "0",
-                        "filename": filename,
-                        "uploaded_filename": upload_filename
-                    }
-                ]
-            }
-        );
+        let req = self.client.post(messages_url(credential.channel_id));
 
-        let req = self.client.post(url);
+        let resp = discord_json(prepare_discord_request(req, credential.token.clone(), &self.arguments.profile)
+            .json(&message_payload(credential.channel_id, filename, upload_filename)))?;
 
-        let resp = prepare_discord_request(req, self.arguments.token.clone()).json(&payload)
-            .send().unwrap().json::<serde_json::Value>().unwrap();
+        parse_message(&resp)
+    }
+}
 
-        let file_url = resp["attachments"][0]["url"].as_str().unwrap();
+/// Send a Discord API request, see `check_status` for the errors
+fn discord_json(request: RequestBuilder) -> std::io::Result<serde_json::Value> {
+    let response = request.send().map_err(std::io::Error::other)?;
 
-        //println!("Message has file url: {:?}", file_url);
+    check_status(response.status(), response.headers())?;
+
+    response.json::<serde_json::Value>().map_err(std::io::Error::other)
+}
+
+// Discord API
+// Requests and responses of an upload, shared with the async uploader:
+// an upload url is requested for the attachment, the container is sent
+// to it, then a message referencing the uploaded attachment is posted
+pub(crate) fn attachments_url(channel_id: u64) -> String {
+    format!("https://discord.com/api/v9/channels/{}/attachments", channel_id)
+}
+
+pub(crate) fn messages_url(channel_id: u64) -> String {
+    format!("https://discord.com/api/v9/channels/{}/messages", channel_id)
+}
 
-        file_url.to_string()
+pub(crate) fn attachment_payload(filename: String, size: u64) -> serde_json::Value {
+    json!(
+        {
+            "files": [
+                {
+                    "filename": filename,
+                    "file_size": size,
+                    "id": "8"
+                }
+            ]
+        }
+    )
+}
+
+/// Upload url and upload filename of the attachment
+pub(crate) fn parse_attachment(response: &serde_json::Value) -> std::io::Result<(String, String)> {
+    let upload_url = response["attachments"][0]["upload_url"].as_str()
+        .ok_or_else(|| unexpected_response(response))?;
+    let upload_filename = response["attachments"][0]["upload_filename"].as_str()
+        .ok_or_else(|| unexpected_response(response))?;
+
+    Ok((upload_url.to_string(), upload_filename.to_string()))
+}
+
+pub(crate) fn message_payload(channel_id: u64, filename: String, upload_filename: String) -> serde_json::Value {
+    json!(
+        {
+            "content": "",
+            "channel_id": channel_id,
+            "type": 0,
+            "attachments": [
+                {
+                    "id": "0",
+                    "filename": filename,
+                    "uploaded_filename": upload_filename
+                }
+            ]
+        }
+    )
+}
+
+/// Id of the posted message and storage url of its attachment
+pub(crate) fn parse_message(response: &serde_json::Value) -> std::io::Result<(u64, String)> {
+    let message_id = response["id"].as_str()
+        .and_then(|id| id.parse().ok())
+        .ok_or_else(|| unexpected_response(response))?;
+    let file_url = response["attachments"][0]["url"].as_str()
+        .ok_or_else(|| unexpected_response(response))?;
+
+    Ok((message_id, file_url.to_string()))
+}
+
+/// Webhooks answer with the posted message only when asked to wait for it
+pub(crate) fn webhook_execute_url(webhook_url: &str) -> String {
+    let separator = if webhook_url.contains('?') { '&' } else { '?' };
+
+    format!("{}{}wait=true", webhook_url, separator)
+}
+
+/// `multipart/form-data` body of a webhook message with one attachment,
+/// the attachment is sent between `head` and `tail`
+pub(crate) struct WebhookForm {
+    pub(crate) content_type: String,
+    pub(crate) head: Vec<u8>,
+    pub(crate) tail: Vec<u8>,
+}
+
+impl WebhookForm {
+    pub(crate) fn new(filename: &str) -> WebhookForm {
+        let boundary = format!("discord-us-{:016x}", rand::random::<u64>());
+
+        let payload = json!({ "attachments": [{ "id": 0, "filename": filename }] });
+
+        let head = format!(
+            "--{boundary}\r\nContent-Disposition: form-data; name=\"payload_json\"\r\nContent-Type: application/json\r\n\r\n{payload}\r\n\
+             --{boundary}\r\nContent-Disposition: form-data; name=\"files[0]\"; filename=\"{filename}\"\r\nContent-Type: application/octet-stream\r\n\r\n"
+        );
+
+        WebhookForm {
+            content_type: format!("multipart/form-data; boundary={}", boundary),
+            head: head.into_bytes(),
+            tail: format!("\r\n--{}--\r\n", boundary).into_bytes(),
+        }
+    }
+
+    /// Size of the body holding an attachment of `attachment_size` bytes
+    pub(crate) fn size(&self, attachment_size: u64) -> u64 {
+        self.head.len() as u64 + attachment_size + self.tail.len() as u64
     }
 }
 
+fn unexpected_response(response: &serde_json::Value) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Unexpected Discord response: {}", response))
+}
+
 
 struct CustomBody {
     key: [u8; 32],
 
     remaining_size: i64,
     file: File,
+    chunk_size: usize,
     buffer_cursor: usize,
     buffer: Vec<u8>,
 
     signal: Option<Box<dyn ReportSignal<u64>>>,
+    source_signal: Option<Box<dyn ReportSignal<ProgressionRange<u64>>>>,
+    // file offset of the next chunk, and the file range of the
+    // chunk in the buffer, reported once the chunk is sent
+    source_cursor: u64,
+    source_range: Option<ProgressionRange<u64>>,
+    control: TransferControl,
+    rate_limiter: Option<RateLimiter>,
+
+    events: ContainerEvents,
+    chunk: u64,
 }
 
 unsafe impl Send for CustomBody {}
@@ -546,57 +1098,68 @@ impl CustomBody {
         let mut salt = [0u8; 16];
         thread_rng().fill_bytes(&mut salt);
 
-        let content_size = min(self.remaining_size as usize, (CHUNK_SIZE as usize) - METADATA_SIZE);
+        let content_size = min(self.remaining_size as usize, self.chunk_size - METADATA_SIZE);
 
         //  println!("Buffer size: {:?}, Content size {:?}", self.buffer.len(), content_size);
 
-        self.file.read(&mut self.buffer[0..content_size]).unwrap();
+        // the last chunk of the file is shorter than content_size,
+        // the tail is zeroed, the downloader truncates it using the container byte range
+        let mut bytes_read = 0;
+        while bytes_read < content_size {
+            let r = self.file.read(&mut self.buffer[bytes_read..content_size]).unwrap();
+            if r == 0 {
+                break;
+            }
+            bytes_read += r;
+        }
+        self.buffer[bytes_read..content_size].fill(0);
 
         // println!("Read {:?} bytes from file", bytes_read);
 
-        // compute hash
-        let mut hasher = Sha256::new();
-        hasher.update(&self.buffer[0..content_size]);
-        let hash = hasher.finalize();
-
-        //println!("Chunk hash: {:?}", hash);
-
-        // encrypt data
-        let cipher = Aes256Cbc::new_from_slices(
-            &self.key.clone(),
-            &salt.clone(),
-        ).unwrap();
-
-        // println!("Encryption key: {:?}", self.key.clone());
-        // println!("Encryption salt: {:?}", salt.clone());
+        encrypt_chunk(&self.key, &salt, &mut self.buffer);
 
-        // println!("Encrypting chunk from 0 to {:?}", content_size + 16);
+        self.remaining_size -= self.chunk_size as i64;
 
-        cipher.encrypt(&mut self.buffer[0..(content_size + 16)], content_size)
-            .expect("encryption failure!");
+        self.source_range = Some(ProgressionRange::of(self.source_cursor, self.source_cursor + bytes_read as u64));
+        self.source_cursor += bytes_read as u64;
 
-        // println!("Setting salt at {:?} -> {:?}", (CHUNK_SIZE as usize) - 48, ((CHUNK_SIZE as usize) - 32));
-
-        // add at end the iv
-        self.buffer[(CHUNK_SIZE as usize) - 48..((CHUNK_SIZE as usize) - 32)].clone_from_slice(&salt.clone());
-
-        self.buffer[(CHUNK_SIZE as usize) - 32..].clone_from_slice(&hash.clone());
-
-        self.remaining_size -= CHUNK_SIZE as i64;
+        self.events.chunk_encrypted(self.chunk, self.chunk_size as u64, bytes_read as u64);
+        self.chunk += 1;
     }
 
-    pub fn new(key: [u8; 32], remaining_size: i64, file_path: String, cursor: i64, signal: Option<Box<dyn ReportSignal<u64>>>) -> CustomBody {
-        let mut file = File::open(file_path.clone()).unwrap();
-        //println!("Seeking to {:?}", cursor);
+    fn report_source_range(&mut self) {
+        if let (Some(signal), Some(range)) = (self.source_signal.as_mut(), self.source_range.take()) {
+            signal.report_data(range);
+        }
+    }
 
-        file.seek(SeekFrom::Current(cursor)).unwrap();
+    /// `file` must be positioned at the start of the container
+    fn new(key: [u8; 32], remaining_size: i64, mut file: File, chunk_size: u32, signal: Option<Box<dyn ReportSignal<u64>>>, arguments: &FileUploadArguments, events: ContainerEvents) -> CustomBody {
+        let cursor = file.stream_position().unwrap();
 
-        CustomBody { key, remaining_size, file, buffer: vec![0; CHUNK_SIZE as usize], buffer_cursor: CHUNK_SIZE as usize, signal }
+        CustomBody {
+            key,
+            remaining_size,
+            file,
+            chunk_size: chunk_size as usize,
+            buffer: vec![0; chunk_size as usize],
+            buffer_cursor: chunk_size as usize,
+            signal,
+            source_signal: arguments.source_signal.clone(),
+            source_cursor: cursor,
+            source_range: None,
+            control: arguments.control.clone(),
+            rate_limiter: arguments.rate_limiter.clone(),
+            events,
+            chunk: 0,
+        }
     }
 }
 
 impl Read for CustomBody {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.control.check_cancelled()?;
+
         let mut read = 0;
 
         //println!("Doing read of {:?}", buf.len());
@@ -604,15 +1167,17 @@ impl Read for CustomBody {
         while read < buf.len() {
             // println!("Read loop: buffer_cursor {:?} (read {:?})", self.buffer_cursor, read);
 
-            if self.buffer_cursor < CHUNK_SIZE as usize {
-                let remain = min(buf.len() - read, CHUNK_SIZE as usize - self.buffer_cursor);
+            if self.buffer_cursor < self.chunk_size {
+                let remain = min(buf.len() - read, self.chunk_size - self.buffer_cursor);
                 buf[read..(read + remain)].clone_from_slice(&self.buffer[self.buffer_cursor..(self.buffer_cursor + remain)]);
                 // println!("Read loop: pushing {:?} buf", remain);
                 read += remain;
                 self.buffer_cursor += remain;
             }
 
-            if self.buffer_cursor >= CHUNK_SIZE as usize {
+            if self.buffer_cursor >= self.chunk_size {
+                self.report_source_range();
+
                 if self.remaining_size <= 0 {
                     //println!("End ! with read = {:?}", read);
                     break;
@@ -624,6 +1189,8 @@ impl Read for CustomBody {
             }
         }
 
+        throttle(&self.rate_limiter, read);
+
         // report read;
 
         if let Some(signal) = self.signal.as_mut() {
@@ -633,3 +1200,120 @@ impl Read for CustomBody {
         Ok(read)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use crate::signal::{ContainerEvents, MultiSignal, PartProgression, ProgressionRange, Signal};
+    use std::fs::File;
+    use std::io::{Seek, SeekFrom};
+    use crate::common::{Subscription, DEFAULT_CHUNK_SIZE, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE};
+    use crate::uploader::{parse_message, webhook_execute_url, CustomBody, FileUploadArguments, FileUploader, ResumableUploader, WebhookForm};
+
+    #[test]
+    fn test_source_progress() {
+        let file_size = 200_000;
+        let file_path = std::env::temp_dir().join(format!("discord-us-test-{}", rand::random::<u64>()));
+        std::fs::write(&file_path, vec![7u8; file_size]).unwrap();
+
+        let source_signal = PartProgression::new();
+        let mut arguments = FileUploadArguments::new(String::new(), String::new(), 0);
+        arguments.with_source_signal(&source_signal);
+
+        let events = ContainerEvents { signal: MultiSignal::new(), worker: 0, index: 1 };
+        let remaining_size = 4 * DEFAULT_CHUNK_SIZE as i64;
+
+        let file = File::open(&file_path).unwrap();
+        let mut body = CustomBody::new([0u8; 32], remaining_size, file, DEFAULT_CHUNK_SIZE, None, &arguments, events);
+
+        let mut encoded = Vec::new();
+        body.read_to_end(&mut encoded).unwrap();
+
+        std::fs::remove_file(&file_path).unwrap();
+
+        assert_eq!(encoded.len() as i64, remaining_size);
+        assert_eq!(source_signal.get_data(), vec![ProgressionRange::of(0, file_size as u64)]);
+    }
+
+    #[test]
+    fn test_chunk_size() {
+        let file_size = 100_000u64;
+        let file_path = std::env::temp_dir().join(format!("discord-us-test-{}", rand::random::<u64>()));
+        std::fs::write(&file_path, vec![7u8; file_size as usize]).unwrap();
+
+        let source_signal = PartProgression::new();
+        let mut arguments = FileUploadArguments::new(String::new(), String::new(), 0);
+        arguments.with_source_signal(&source_signal);
+
+        // second container of 2 chunks of MIN_CHUNK_SIZE
+        let cursor = 2 * (MIN_CHUNK_SIZE as u64 - 64);
+        let mut file = File::open(&file_path).unwrap();
+        file.seek(SeekFrom::Start(cursor)).unwrap();
+
+        let events = ContainerEvents { signal: MultiSignal::new(), worker: 0, index: 2 };
+        let mut body = CustomBody::new([0u8; 32], 2 * MIN_CHUNK_SIZE as i64, file, MIN_CHUNK_SIZE, None, &arguments, events);
+
+        let mut encoded = Vec::new();
+        body.read_to_end(&mut encoded).unwrap();
+
+        std::fs::remove_file(&file_path).unwrap();
+
+        assert_eq!(encoded.len() as u64, 2 * MIN_CHUNK_SIZE as u64);
+        assert_eq!(source_signal.get_data(), vec![ProgressionRange::of(cursor, cursor + 2 * (MIN_CHUNK_SIZE as u64 - 64))]);
+
+        assert!(FileUploader::check_chunk_size(DEFAULT_CHUNK_SIZE).is_ok());
+        assert!(FileUploader::check_chunk_size(MIN_CHUNK_SIZE).is_ok());
+        assert!(FileUploader::check_chunk_size(MAX_CHUNK_SIZE).is_ok());
+        assert!(FileUploader::check_chunk_size(MIN_CHUNK_SIZE / 2).is_err());
+        assert!(FileUploader::check_chunk_size(MAX_CHUNK_SIZE * 2).is_err());
+        assert!(FileUploader::check_chunk_size(3 * MIN_CHUNK_SIZE).is_err());
+    }
+
+    #[test]
+    fn test_resume_session_moved_file() {
+        let file_path = std::env::temp_dir().join(format!("discord-us-test-{}", rand::random::<u64>()));
+        std::fs::write(&file_path, vec![7u8; 1000]).unwrap();
+
+        let uploader = FileUploader::new(file_path.to_string_lossy().to_string(), DEFAULT_CHUNK_SIZE);
+        std::fs::remove_file(&file_path).unwrap();
+
+        // the file cannot be hashed anymore
+        let err = uploader.export_resume_session().err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_container_size() {
+        for subscription in [Subscription::Free, Subscription::Basic, Subscription::Classic, Subscription::Boost] {
+            assert!(FileUploader::check_container_size(subscription.get_max_chunk_upload_size() as u64, DEFAULT_CHUNK_SIZE).is_ok());
+        }
+
+        assert!(FileUploader::check_container_size(0, DEFAULT_CHUNK_SIZE).is_err());
+        assert!(FileUploader::check_container_size(DEFAULT_CHUNK_SIZE as u64 + 1, DEFAULT_CHUNK_SIZE).is_err());
+        assert!(FileUploader::check_container_size(MIN_CHUNK_SIZE as u64 * 3, MIN_CHUNK_SIZE).is_ok());
+        assert!(FileUploader::check_container_size(MIN_CHUNK_SIZE as u64 * 3, DEFAULT_CHUNK_SIZE).is_err());
+        assert!(FileUploader::check_container_size(1 << 32, DEFAULT_CHUNK_SIZE).is_err());
+
+        assert!(FileUploader::check_webhook_container_size(Subscription::Free.get_container_size(DEFAULT_CHUNK_SIZE) as u64).is_ok());
+        assert!(FileUploader::check_webhook_container_size(Subscription::Basic.get_container_size(DEFAULT_CHUNK_SIZE) as u64).is_err());
+    }
+
+    #[test]
+    fn test_webhook_form() {
+        assert_eq!(webhook_execute_url("https://discord.com/api/webhooks/1/abc"), "https://discord.com/api/webhooks/1/abc?wait=true");
+        assert_eq!(webhook_execute_url("https://discord.com/api/webhooks/1/abc?thread_id=2"), "https://discord.com/api/webhooks/1/abc?thread_id=2&wait=true");
+
+        let form = WebhookForm::new("data.enc");
+        let boundary = form.content_type.strip_prefix("multipart/form-data; boundary=").unwrap();
+
+        let head = String::from_utf8(form.head.clone()).unwrap();
+        assert!(head.starts_with(&format!("--{}\r\n", boundary)));
+        assert!(head.ends_with("name=\"files[0]\"; filename=\"data.enc\"\r\nContent-Type: application/octet-stream\r\n\r\n"));
+        assert_eq!(form.tail, format!("\r\n--{}--\r\n", boundary).into_bytes());
+        assert_eq!(form.size(10), (form.head.len() + 10 + form.tail.len()) as u64);
+
+        let message = serde_json::json!({ "id": "1234", "attachments": [{ "url": "https://cdn.discordapp.com/attachments/1/2/data.enc" }] });
+        assert_eq!(parse_message(&message).unwrap(), (1234, "https://cdn.discordapp.com/attachments/1/2/data.enc".to_string()));
+        assert!(parse_message(&serde_json::json!({ "attachments": [] })).is_err());
+    }
+}
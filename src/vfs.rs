@@ -0,0 +1,398 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::Read;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+use crate::cache::ChunkCache;
+use crate::catalog::Catalog;
+use crate::common::{FileReadable, Waterfall};
+use crate::downloader::{ByteRangeDownloader, FileDownloader, WaterfallDownloader};
+
+// Virtual filesystem
+// Flat read-only directory of the files of several waterfalls, meant to
+// back a filesystem mount. Reads are rounded to blocks and served by the
+// ranged downloader, so sequential small reads do not each open a stream.
+
+/// Inode of the directory, files are numbered from `ROOT_INODE + 1`
+pub const ROOT_INODE: u64 = 1;
+
+pub const DEFAULT_BLOCK_SIZE: u64 = 1024 * 1024;
+pub const DEFAULT_CACHED_BLOCKS: usize = 64;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileAttributes {
+    pub inode: u64,
+    pub name: String,
+    pub size: u64,
+    /// Unix timestamp in seconds
+    pub modified: u64,
+}
+
+/// Waterfall left out of the directory because it could not be added
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SkippedEntry {
+    /// Path of the waterfall file, or catalog id
+    pub source: String,
+    pub error: String,
+}
+
+struct VirtualFile {
+    attributes: FileAttributes,
+    downloader: FileDownloader,
+}
+
+pub struct WaterfallFs {
+    files: Vec<VirtualFile>,
+    skipped: Vec<SkippedEntry>,
+    block_size: u64,
+    cache: Mutex<BlockCache>,
+    chunk_cache: Option<ChunkCache>,
+}
+
+impl WaterfallFs {
+    pub fn new() -> WaterfallFs {
+        WaterfallFs::with_cache(DEFAULT_BLOCK_SIZE, DEFAULT_CACHED_BLOCKS)
+    }
+
+    /// Memory use is bounded by `block_size * cached_blocks`
+    pub fn with_cache(block_size: u64, cached_blocks: usize) -> WaterfallFs {
+        WaterfallFs {
+            files: Vec::new(),
+            skipped: Vec::new(),
+            block_size: block_size.max(1),
+            cache: Mutex::new(BlockCache::new(cached_blocks)),
+            chunk_cache: None,
+        }
+    }
+
+    /// Keep the chunks of the files across mounts, the files already added
+    /// by `from_directory` or `from_catalog` included
+    pub fn with_chunk_cache(&mut self, cache: &ChunkCache) {
+        for file in self.files.iter_mut() {
+            file.downloader.with_cache(cache);
+        }
+
+        self.chunk_cache = Some(cache.clone());
+    }
+
+    /// Every waterfall of the directory, other files are ignored.
+    /// Waterfalls that cannot be added, such as a root pointer whose manifest
+    /// fails to download, are listed by `get_skipped`.
+    pub fn from_directory<P: AsRef<Path>>(path: P, password: Option<String>) -> std::io::Result<WaterfallFs> {
+        let mut fs = WaterfallFs::new();
+
+        let mut paths: Vec<_> = std::fs::read_dir(path)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file())
+            .collect();
+        paths.sort();
+
+        for path in paths {
+            let Ok(waterfall) = Waterfall::from_file(path.to_string_lossy().to_string()) else {
+                continue;
+            };
+
+            let modified = std::fs::metadata(&path)?.modified()?
+                .duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+
+            if let Err(err) = fs.add(waterfall, password.clone(), modified) {
+                fs.skip(path.to_string_lossy().to_string(), &err);
+            }
+        }
+
+        Ok(fs)
+    }
+
+    /// Every upload of the catalog, see `from_directory` for skipped entries
+    pub fn from_catalog(catalog: &Catalog, password: Option<String>) -> std::io::Result<WaterfallFs> {
+        let mut fs = WaterfallFs::new();
+
+        for entry in catalog.list()? {
+            if let Some(waterfall) = catalog.get_waterfall(entry.id)? {
+                if let Err(err) = fs.add(waterfall, password.clone(), entry.uploaded_at) {
+                    fs.skip(entry.id.to_string(), &err);
+                }
+            }
+        }
+
+        Ok(fs)
+    }
+
+    /// Add the file of a waterfall, resolving root pointers, and return its inode.
+    /// The name is the file name of the waterfall, suffixed if already taken.
+    pub fn add(&mut self, waterfall: Waterfall, password: Option<String>, modified: u64) -> std::io::Result<u64> {
+        let mut downloader = FileDownloader::from_waterfall(waterfall);
+
+        if let Some(password) = password {
+            downloader.set_password(password);
+        }
+
+        if let Some(cache) = &self.chunk_cache {
+            downloader.with_cache(cache);
+        }
+
+        downloader.resolve_root_pointer()?;
+
+        let filename = downloader.get_waterfall().filename.clone();
+        let base_name = Path::new(&filename).file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| "file".to_string());
+
+        let mut name = base_name.clone();
+        let mut n = 1;
+
+        while self.lookup(&name).is_some() {
+            n += 1;
+            name = format!("{} ({})", base_name, n);
+        }
+
+        let inode = ROOT_INODE + 1 + self.files.len() as u64;
+
+        self.files.push(VirtualFile {
+            attributes: FileAttributes { inode, name, size: downloader.get_size(), modified },
+            downloader,
+        });
+
+        Ok(inode)
+    }
+
+    /// Waterfalls left out by `from_directory` and `from_catalog`
+    pub fn get_skipped(&self) -> &[SkippedEntry] {
+        &self.skipped
+    }
+
+    fn skip(&mut self, source: String, err: &std::io::Error) {
+        self.skipped.push(SkippedEntry { source, error: err.to_string() });
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<u64> {
+        self.files.iter()
+            .find(|file| file.attributes.name == name)
+            .map(|file| file.attributes.inode)
+    }
+
+    pub fn get_attributes(&self, inode: u64) -> Option<&FileAttributes> {
+        self.get_file(inode).map(|file| &file.attributes)
+    }
+
+    /// Files of the directory, in inode order
+    pub fn list(&self) -> Vec<&FileAttributes> {
+        self.files.iter().map(|file| &file.attributes).collect()
+    }
+
+    /// Read up to `size` bytes at `offset`, fewer at the end of the file
+    pub fn read(&self, inode: u64, offset: u64, size: usize) -> std::io::Result<Vec<u8>> {
+        let file = self.get_file(inode)
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("No file with inode {}", inode)))?;
+
+        let end = file.attributes.size.min(offset.saturating_add(size as u64));
+        let mut data = Vec::with_capacity(end.saturating_sub(offset) as usize);
+
+        let mut position = offset;
+
+        while position < end {
+            let block = position / self.block_size;
+            let block_start = block * self.block_size;
+
+            let bytes = self.cache.lock().unwrap().get(&(inode, block));
+
+            let bytes = match bytes {
+                Some(bytes) => bytes,
+                None => {
+                    let block_end = file.attributes.size.min(block_start + self.block_size);
+
+                    let mut bytes = Vec::with_capacity((block_end - block_start) as usize);
+                    file.downloader.get_range(block_start, block_end).read_to_end(&mut bytes)?;
+
+                    self.cache.lock().unwrap().insert((inode, block), bytes.clone());
+                    bytes
+                }
+            };
+
+            let from = (position - block_start) as usize;
+            let to = bytes.len().min((end - block_start) as usize);
+
+            data.extend_from_slice(&bytes[from..to]);
+            position = block_start + to as u64;
+        }
+
+        Ok(data)
+    }
+
+    fn get_file(&self, inode: u64) -> Option<&VirtualFile> {
+        inode.checked_sub(ROOT_INODE + 1)
+            .and_then(|index| self.files.get(index as usize))
+    }
+}
+
+impl Default for WaterfallFs {
+    fn default() -> Self {
+        WaterfallFs::new()
+    }
+}
+
+/// Least recently used blocks of files, by inode and block index
+struct BlockCache {
+    capacity: usize,
+    blocks: HashMap<(u64, u64), Vec<u8>>,
+    order: VecDeque<(u64, u64)>,
+}
+
+impl BlockCache {
+    fn new(capacity: usize) -> BlockCache {
+        BlockCache {
+            capacity,
+            blocks: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn get(&mut self, key: &(u64, u64)) -> Option<Vec<u8>> {
+        let bytes = self.blocks.get(key)?.clone();
+        self.touch(key);
+        Some(bytes)
+    }
+
+    fn insert(&mut self, key: (u64, u64), bytes: Vec<u8>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if self.blocks.insert(key, bytes).is_some() {
+            self.touch(&key);
+            return;
+        }
+
+        self.order.push_back(key);
+
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.blocks.remove(&oldest);
+            }
+        }
+    }
+
+    fn touch(&mut self, key: &(u64, u64)) {
+        if let Some(position) = self.order.iter().position(|k| k == key) {
+            self.order.remove(position);
+            self.order.push_back(*key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cache::ChunkCache;
+    use crate::common::{Container, Waterfall, WaterfallFormat};
+    use crate::downloader::serve_container;
+    use crate::vfs::{BlockCache, WaterfallFs, ROOT_INODE};
+
+    fn empty_waterfall(filename: &str) -> Waterfall {
+        Waterfall {
+            filename: filename.to_string(),
+            password: String::new(),
+            size: 0,
+            file_hash: None,
+            manifest_level: 0,
+            containers: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_names() {
+        let mut fs = WaterfallFs::new();
+
+        let a = fs.add(empty_waterfall("/home/me/movie.mkv"), None, 5).unwrap();
+        let b = fs.add(empty_waterfall("movie.mkv"), None, 6).unwrap();
+        let c = fs.add(empty_waterfall(""), None, 7).unwrap();
+
+        assert_eq!(a, ROOT_INODE + 1);
+        assert_eq!(fs.lookup("movie.mkv"), Some(a));
+        assert_eq!(fs.lookup("movie.mkv (2)"), Some(b));
+        assert_eq!(fs.get_attributes(c).map(|f| f.name.as_str()), Some("file"));
+        assert_eq!(fs.list().len(), 3);
+
+        assert!(fs.get_attributes(ROOT_INODE).is_none());
+        assert_eq!(fs.read(a, 0, 10).unwrap(), Vec::<u8>::new());
+        assert!(fs.read(42, 0, 10).is_err());
+    }
+
+    #[test]
+    fn test_skipped_root_pointer() {
+        let directory = std::env::temp_dir().join(format!("discord-us-vfs-{}", rand::random::<u64>()));
+        std::fs::create_dir(&directory).unwrap();
+
+        let container = |size: u64| Container::test_fixture(1, [0, size]);
+
+        let mut file = empty_waterfall("file.bin");
+        file.size = 100;
+        file.containers = vec![container(100)];
+
+        // the manifest is too large, it fails before any download
+        let mut root_pointer = empty_waterfall("manifest.bin");
+        root_pointer.size = 64 * 1024 * 1024 + 1;
+        root_pointer.manifest_level = 1;
+        root_pointer.containers = vec![container(root_pointer.size)];
+
+        let root_pointer_path = directory.join("a.waterfall");
+        root_pointer.write_to_file_as(root_pointer_path.to_string_lossy().to_string(), WaterfallFormat::Json).unwrap();
+        file.write_to_file_as(directory.join("b.waterfall").to_string_lossy().to_string(), WaterfallFormat::Json).unwrap();
+        std::fs::write(directory.join("notes.txt"), "not a waterfall").unwrap();
+
+        let fs = WaterfallFs::from_directory(&directory, None);
+        std::fs::remove_dir_all(&directory).unwrap();
+        let fs = fs.unwrap();
+
+        assert_eq!(fs.list().len(), 1);
+        assert!(fs.lookup("file.bin").is_some());
+        assert_eq!(fs.get_skipped().len(), 1);
+        assert_eq!(fs.get_skipped()[0].source, root_pointer_path.to_string_lossy());
+    }
+
+    #[test]
+    fn test_directory_chunk_cache() {
+        let directory = std::env::temp_dir().join(format!("discord-us-vfs-{}", rand::random::<u64>()));
+        std::fs::create_dir(&directory).unwrap();
+
+        let content: Vec<u8> = (0..100).collect();
+
+        let mut waterfall = empty_waterfall("file.bin");
+        waterfall.size = content.len() as u64;
+        waterfall.containers = vec![Container {
+            storage_url: serve_container(&content, 1024, usize::MAX),
+            chunk_size: 1024,
+            ..Container::test_fixture(1, [0, content.len() as u64])
+        }];
+        waterfall.write_to_file_as(directory.join("file.waterfall").to_string_lossy().to_string(), WaterfallFormat::Json).unwrap();
+
+        let mut fs = WaterfallFs::from_directory(&directory, None).unwrap();
+        let cache = ChunkCache::open(directory.join("cache"), 1 << 20).unwrap();
+        fs.with_chunk_cache(&cache);
+
+        let inode = fs.lookup("file.bin").unwrap();
+        let read = fs.read(inode, 0, 100);
+
+        let cached = cache.contains(&waterfall.containers[0], 0);
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(read.unwrap(), content);
+        assert!(cached);
+    }
+
+    #[test]
+    fn test_block_cache() {
+        let mut cache = BlockCache::new(2);
+
+        cache.insert((1, 0), vec![0]);
+        cache.insert((1, 1), vec![1]);
+        assert_eq!(cache.get(&(1, 0)), Some(vec![0]));
+
+        // (1, 1) is the least recently used
+        cache.insert((2, 0), vec![2]);
+        assert_eq!(cache.get(&(1, 1)), None);
+        assert_eq!(cache.get(&(1, 0)), Some(vec![0]));
+        assert_eq!(cache.get(&(2, 0)), Some(vec![2]));
+    }
+}